@@ -0,0 +1,76 @@
+//! Benchmarks the connectivity probe against fake targets with fixed latencies.
+//!
+//! Targets are tried one after another, so every unreachable target ahead of a
+//! reachable one adds its full timeout to the probe.
+
+use aw_watcher_network_rs::{Connector, ProbeSuccess, Resolver, probe_targets};
+use criterion::{Criterion, criterion_group, criterion_main};
+use std::collections::HashMap;
+use std::hint::black_box;
+use std::io;
+use std::net::SocketAddr;
+use std::thread::sleep;
+use std::time::Duration;
+
+/// Latency of a reachable fake target
+const CONNECT_LATENCY: Duration = Duration::from_millis(1);
+
+/// Time an unreachable fake target takes to fail, standing in for the probe timeout
+const FAKE_TIMEOUT: Duration = Duration::from_millis(5);
+
+/// Connector that sleeps for a fixed latency per target instead of touching the network
+struct FakeConnector {
+    /// Latency of each reachable target; any other target times out
+    latencies: HashMap<SocketAddr, Duration>,
+}
+
+impl Connector for FakeConnector {
+    fn connect(&self, addr: &SocketAddr, _timeout: Duration) -> io::Result<ProbeSuccess> {
+        match self.latencies.get(addr) {
+            Some(latency) => {
+                sleep(*latency);
+                Ok(ProbeSuccess {
+                    local_addr: None,
+                    latency: *latency,
+                })
+            }
+            None => {
+                sleep(FAKE_TIMEOUT);
+                Err(io::ErrorKind::TimedOut.into())
+            }
+        }
+    }
+}
+
+fn bench_probe(c: &mut Criterion) {
+    let targets: Vec<String> = ["1.1.1.1:53", "8.8.8.8:53", "9.9.9.9:53"]
+        .iter()
+        .map(|target| target.to_string())
+        .collect();
+
+    let scenarios = [
+        ("first target reachable", Some(0)),
+        ("last target reachable", Some(targets.len() - 1)),
+        ("no target reachable", None),
+    ];
+
+    // Targets are IP addresses, so resolving them doesn't touch the network either
+    let resolver = Resolver::new(Duration::ZERO);
+
+    let mut group = c.benchmark_group("sequential probe");
+    for (name, reachable) in scenarios {
+        let latencies = reachable
+            .map(|index| (targets[index].parse().unwrap(), CONNECT_LATENCY))
+            .into_iter()
+            .collect();
+        let connector = FakeConnector { latencies };
+
+        group.bench_function(name, |b| {
+            b.iter(|| probe_targets(&connector, &resolver, black_box(&targets), FAKE_TIMEOUT))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_probe);
+criterion_main!(benches);
@@ -0,0 +1,242 @@
+//! Connectivity probing used by the aw-watcher-network-rs binary, exposed as a
+//! library so benchmarks and tests can probe with a fake connector
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::{Duration, Instant};
+use surge_ping::{Client, Config, ICMP, PingIdentifier, PingSequence};
+use tokio::runtime::{Builder, Runtime};
+
+/// Time to wait for each probe connection, unless configured otherwise
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Details of a successful connectivity probe
+#[derive(Debug, Clone, Copy)]
+pub struct ProbeSuccess {
+    /// Local address the successful connection was made from
+    pub local_addr: Option<SocketAddr>,
+    /// Time it took to establish the connection
+    pub latency: Duration,
+}
+
+/// Opens the connections used to probe connectivity
+pub trait Connector {
+    /// Connect to `addr`, giving up after `timeout`
+    fn connect(&self, addr: &SocketAddr, timeout: Duration) -> io::Result<ProbeSuccess>;
+}
+
+/// Probes by opening a TCP connection
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TcpConnector;
+
+impl Connector for TcpConnector {
+    fn connect(&self, addr: &SocketAddr, timeout: Duration) -> io::Result<ProbeSuccess> {
+        let connect_start = Instant::now();
+        let stream = TcpStream::connect_timeout(addr, timeout)?;
+        Ok(ProbeSuccess {
+            local_addr: stream.local_addr().ok(),
+            latency: connect_start.elapsed(),
+        })
+    }
+}
+
+/// Probes by sending an ICMP echo request, ignoring the port of the target.
+/// Replies count as a success with the round-trip time as latency
+pub struct PingConnector {
+    runtime: Runtime,
+    client_v4: Client,
+    /// Missing when the system has no IPv6 ICMP socket
+    client_v6: Option<Client>,
+    /// Sequence number of the next echo request
+    sequence: AtomicU16,
+}
+
+impl PingConnector {
+    /// Open the ICMP sockets. Fails when the OS doesn't allow them, e.g. without
+    /// root on Linux outside `net.ipv4.ping_group_range`, or without admin rights on Windows
+    pub fn new() -> io::Result<Self> {
+        let runtime = Builder::new_current_thread().enable_all().build()?;
+        // The sockets register with the runtime they are created in
+        let _guard = runtime.enter();
+        let client_v4 = Client::new(&Config::default())?;
+        let client_v6 = Client::new(&Config::builder().kind(ICMP::V6).build()).ok();
+        Ok(Self {
+            runtime,
+            client_v4,
+            client_v6,
+            sequence: AtomicU16::new(0),
+        })
+    }
+}
+
+impl Connector for PingConnector {
+    fn connect(&self, addr: &SocketAddr, timeout: Duration) -> io::Result<ProbeSuccess> {
+        let client = match addr {
+            SocketAddr::V4(_) => &self.client_v4,
+            SocketAddr::V6(_) => self
+                .client_v6
+                .as_ref()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "no IPv6 ICMP socket"))?,
+        };
+        let sequence = PingSequence(self.sequence.fetch_add(1, Ordering::Relaxed));
+
+        self.runtime.block_on(async {
+            let mut pinger = client
+                .pinger(addr.ip(), PingIdentifier(std::process::id() as u16))
+                .await;
+            pinger.timeout(timeout);
+            let (_, latency) = pinger
+                .ping(sequence, &[0; 8])
+                .await
+                .map_err(io::Error::other)?;
+            Ok(ProbeSuccess {
+                local_addr: None,
+                latency,
+            })
+        })
+    }
+}
+
+/// Resolves `host:port` probe targets, reusing successful resolutions for a fixed time
+#[derive(Debug)]
+pub struct Resolver {
+    /// How long a resolution is reused; zero resolves every time
+    ttl: Duration,
+    /// Address each target resolved to, and when
+    cache: Mutex<HashMap<String, (Instant, SocketAddr)>>,
+}
+
+impl Resolver {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Address of a target, from the cache while it is fresh. Failed lookups
+    /// aren't cached so they are retried on the next probe
+    pub fn resolve(&self, target: &str) -> Option<SocketAddr> {
+        if !self.ttl.is_zero()
+            && let Some((resolved_at, addr)) = self.cache.lock().unwrap().get(target)
+            && resolved_at.elapsed() < self.ttl
+        {
+            return Some(*addr);
+        }
+
+        let addr = target.to_socket_addrs().ok()?.next()?;
+        if !self.ttl.is_zero() {
+            self.cache
+                .lock()
+                .unwrap()
+                .insert(target.to_string(), (Instant::now(), addr));
+        }
+        Some(addr)
+    }
+}
+
+/// Split a target into its `host:port` and the timeout of an optional
+/// `@<n>ms` or `@<n>s` suffix, e.g. `192.168.1.1:80@50ms`
+pub fn parse_target(target: &str) -> Result<(&str, Option<Duration>), String> {
+    let Some((address, timeout)) = target.rsplit_once('@') else {
+        return Ok((target, None));
+    };
+
+    let parsed = match timeout.strip_suffix("ms") {
+        Some(millis) => millis.parse().ok().map(Duration::from_millis),
+        None => timeout
+            .strip_suffix('s')
+            .and_then(|secs| secs.parse().ok())
+            .map(Duration::from_secs),
+    };
+    match parsed {
+        Some(timeout) if !timeout.is_zero() => Ok((address, Some(timeout))),
+        _ => Err(format!(
+            "Invalid timeout '{}' in probe target '{}', expected e.g. @500ms or @2s",
+            timeout, target
+        )),
+    }
+}
+
+/// Try the targets in order and return details of the first successful connection.
+/// Targets without their own timeout wait for `default_timeout`
+pub fn probe_targets<C: Connector>(
+    connector: &C,
+    resolver: &Resolver,
+    targets: &[String],
+    default_timeout: Duration,
+) -> Option<ProbeSuccess> {
+    for target in targets {
+        let Ok((address, timeout)) = parse_target(target) else {
+            continue;
+        };
+
+        // Resolve the address and attempt to establish a connection
+        if let Some(addr) = resolver.resolve(address)
+            && let Ok(success) = connector.connect(&addr, timeout.unwrap_or(default_timeout))
+        {
+            return Some(success);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_target_has_no_timeout() {
+        assert_eq!(parse_target("1.1.1.1:53"), Ok(("1.1.1.1:53", None)));
+    }
+
+    #[test]
+    fn target_with_millisecond_timeout() {
+        assert_eq!(
+            parse_target("192.168.1.1:80@500ms"),
+            Ok(("192.168.1.1:80", Some(Duration::from_millis(500))))
+        );
+    }
+
+    #[test]
+    fn target_with_second_timeout() {
+        assert_eq!(
+            parse_target("example.com:443@2s"),
+            Ok(("example.com:443", Some(Duration::from_secs(2))))
+        );
+    }
+
+    #[test]
+    fn bracketed_ipv6_target_with_timeout() {
+        assert_eq!(
+            parse_target("[2606:4700:4700::1111]:53@500ms"),
+            Ok((
+                "[2606:4700:4700::1111]:53",
+                Some(Duration::from_millis(500))
+            ))
+        );
+    }
+
+    #[test]
+    fn malformed_timeouts_are_rejected() {
+        for target in [
+            "1.1.1.1:53@",
+            "1.1.1.1:53@500",
+            "1.1.1.1:53@fast",
+            "1.1.1.1:53@ms",
+            "1.1.1.1:53@-1s",
+            "1.1.1.1:53@1.5s",
+        ] {
+            assert!(parse_target(target).is_err(), "{} was accepted", target);
+        }
+    }
+
+    #[test]
+    fn zero_timeouts_are_rejected() {
+        assert!(parse_target("1.1.1.1:53@0ms").is_err());
+        assert!(parse_target("1.1.1.1:53@0s").is_err());
+    }
+}
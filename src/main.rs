@@ -1,22 +1,97 @@
 use aw_client_rust::blocking::AwClient;
-use aw_models::Event;
-use chrono::{TimeDelta, Utc};
+use aw_models::{Bucket, Event, Info};
+use aw_watcher_network_rs::{
+    PROBE_TIMEOUT, PingConnector, ProbeSuccess, Resolver, TcpConnector, parse_target, probe_targets,
+};
+use chrono::{Datelike, Local, SecondsFormat, TimeDelta, Timelike, Utc, Weekday};
+use clap::Parser;
 use config::{Config, ConfigError, File};
-use dirs::config_dir;
+use dirs::{config_dir, data_dir};
 use hostname::get as get_hostname;
+use if_addrs::{IfAddr, get_if_addrs};
+use rand::seq::SliceRandom;
 use regex::Regex;
+use rumqttc::{Client as MqttClient, MqttOptions, QoS};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
-use std::collections::HashSet;
-use std::fs::{create_dir_all, write};
-use std::net::{TcpStream, ToSocketAddrs};
-use std::process::Command;
-use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::env;
+use std::fs::{OpenOptions, create_dir_all, read_dir, write};
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, OnceLock};
 use std::thread::{self, sleep};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// Version string shown by `--version`, including the git commit and target triple
+const VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    " (",
+    env!("GIT_HASH"),
+    ", ",
+    env!("BUILD_TARGET"),
+    ")"
+);
+
+/// Command-line arguments for aw-watcher-network
+#[derive(Debug, Parser)]
+#[command(version = VERSION, about = "Network connectivity and Wi-Fi watcher for ActivityWatch")]
+struct Cli {
+    /// Write a commented config file listing every option with its default value, then exit
+    #[arg(long)]
+    generate_config: bool,
+
+    /// Overwrite an existing config file when used with --generate-config
+    #[arg(long, requires = "generate_config")]
+    force: bool,
+
+    /// Print a JSON Schema of the config file, for editors and validation tools, then exit
+    #[arg(long)]
+    config_schema: bool,
+
+    /// Check the connection to aw-server by writing an event to a temporary bucket and
+    /// reading it back, then exit
+    #[arg(long)]
+    selftest: bool,
+
+    /// Run the Wi-Fi scan once, print the raw output of the scanning commands and
+    /// the parsed result, then exit
+    #[arg(long)]
+    debug_wifi: bool,
+
+    /// Print the version of the aw-server the watcher connects to, then exit
+    #[arg(long)]
+    check_server: bool,
+
+    /// Copy the events of an old bucket, e.g. from before the hostname changed, into the
+    /// current bucket of the same type, then exit
+    #[arg(long, value_name = "OLD_ID")]
+    merge_bucket: Option<String>,
+
+    /// Delete the old bucket after --merge-bucket copied its events
+    #[arg(long, requires = "merge_bucket")]
+    delete_old: bool,
+
+    /// Don't create a default config file when there is none, e.g. on a read-only filesystem
+    #[arg(long)]
+    read_only_config: bool,
+
+    /// Stop after this many seconds, sending a final event before exiting
+    #[arg(long, value_name = "SECONDS")]
+    duration: Option<u64>,
+
+    /// Test-only: shorten every polling and scan interval to 50ms (same as AW_NETWORK_TEST_FAST=1)
+    #[arg(long, hide = true)]
+    test_fast: bool,
+}
 
 /// Configuration structure for aw-watcher-network
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 struct AppConfig {
     /// Polling interval in seconds
     #[serde(default = "default_polling_interval")]
@@ -25,6 +100,367 @@ struct AppConfig {
     /// Wi-Fi SSID scanning interval in seconds
     #[serde(default = "default_wifi_scan_interval")]
     wifi_scan_interval: u64,
+
+    /// Wi-Fi scanning interval in seconds used instead while on AC power
+    #[serde(default)]
+    wifi_scan_interval_docked: Option<u64>,
+
+    /// Additionally probe connectivity over IPv6 and report it in the network event
+    #[serde(default)]
+    check_ipv6: bool,
+
+    /// Command (with arguments) used to scan for Wi-Fi networks on Linux instead of nmcli/iwlist
+    #[serde(default)]
+    linux_scan_command: Option<String>,
+
+    /// Tools tried in order to scan for Wi-Fi networks on Linux: "nmcli", "iwlist" and/or "iw"
+    #[serde(default = "default_linux_wifi_backends")]
+    linux_wifi_backends: Vec<String>,
+
+    /// Never scan with Linux backends that need root ("iwlist", "iw") when not running as root
+    #[serde(default)]
+    require_unprivileged_scan: bool,
+
+    /// Command (with arguments) used to scan for Wi-Fi networks on macOS instead of system_profiler
+    #[serde(default)]
+    macos_scan_command: Option<String>,
+
+    /// Command (with arguments) used to scan for Wi-Fi networks on Windows instead of netsh
+    #[serde(default)]
+    windows_scan_command: Option<String>,
+
+    /// Polling interval in seconds used while the network has been offline for a while
+    #[serde(default)]
+    offline_polling_interval: Option<u64>,
+
+    /// Create the buckets at startup; disable if they are pre-provisioned on the server
+    #[serde(default = "default_create_buckets")]
+    create_buckets: bool,
+
+    /// Include the connected Wi-Fi SSID in network events
+    #[serde(default)]
+    include_ssid_in_network_event: bool,
+
+    /// Connectivity probe interval in seconds; heartbeats are still sent every polling interval
+    /// or immediately when the state changes
+    #[serde(default)]
+    probe_interval: Option<u64>,
+
+    /// Poll on multiples of the interval on the system clock instead of relative to startup
+    #[serde(default)]
+    align_to_clock: bool,
+
+    /// Report whether the successful probe went over the system's default route interface
+    #[serde(default)]
+    include_default_route: bool,
+
+    /// Send scan and heartbeat errors as events to a diagnostics bucket
+    #[serde(default)]
+    emit_diagnostics: bool,
+
+    /// Skip network heartbeats whose data is the same as the last one sent
+    #[serde(default)]
+    dedup_network_events: bool,
+
+    /// With dedup_network_events, resend an unchanged network event after this many seconds
+    #[serde(default = "default_max_heartbeat_gap_seconds")]
+    max_heartbeat_gap_seconds: u64,
+
+    /// Send a heartbeat at least this often, in seconds, even when transitions mode,
+    /// dedup_network_events or suppress_repeated_empty_wifi would skip it; 0 disables
+    #[serde(default)]
+    keepalive_interval_seconds: u64,
+
+    /// Milliseconds to wait before each retry of startup bucket creation and of
+    /// the first heartbeat to a bucket
+    #[serde(default = "default_retry_schedule_ms")]
+    retry_schedule_ms: Vec<u64>,
+
+    /// Keep retrying with the last delay of retry_schedule_ms instead of giving up
+    #[serde(default)]
+    retry_schedule_repeat: bool,
+
+    /// Send an event to a Wi-Fi transitions bucket each time the connected network changes
+    #[serde(default)]
+    emit_wifi_transitions: bool,
+
+    /// Send an event to a proxy changes bucket each time the system proxy changes
+    #[serde(default)]
+    track_proxy_changes: bool,
+
+    /// Port used for the built-in DNS server probe targets
+    #[serde(default = "default_probe_port")]
+    default_probe_port: u16,
+
+    /// Milliseconds to wait for each probe connection, unless a target sets its own timeout
+    #[serde(default = "default_connect_timeout_ms")]
+    connect_timeout_ms: u64,
+
+    /// How connectivity is checked: "tcp" or "ping" probes the targets, "script" runs connectivity_script
+    #[serde(default = "default_connectivity_mode")]
+    connectivity_mode: String,
+
+    /// Program run each poll in "script" connectivity mode; exit code 0 means online
+    #[serde(default)]
+    connectivity_script: Option<String>,
+
+    /// Custom probe targets (`host:port`); replaces the built-in DNS servers when set
+    #[serde(default)]
+    connectivity_targets: Vec<String>,
+
+    /// Probe only this many randomly chosen connectivity targets each poll
+    #[serde(default)]
+    targets_per_poll: Option<usize>,
+
+    /// Target (`host:port`) whose own reachability is reported, independent of the overall result
+    #[serde(default)]
+    primary_target: Option<String>,
+
+    /// Seconds to reuse resolved probe target addresses for; 0 resolves on every probe
+    #[serde(default)]
+    dns_cache_ttl_seconds: u64,
+
+    /// LAN `host:port` (e.g. the router) probed to tell local outages from upstream ones
+    #[serde(default)]
+    lan_probe_target: Option<String>,
+
+    /// Run the connectivity watcher (network bucket and main polling loop)
+    #[serde(default = "default_enable_watcher")]
+    enable_network_watcher: bool,
+
+    /// Run the Wi-Fi watcher (Wi-Fi bucket and scanning thread)
+    #[serde(default = "default_enable_watcher")]
+    enable_wifi_watcher: bool,
+
+    /// Labels shown as the Wi-Fi event title instead of the SSID, keyed by SSID
+    #[serde(default)]
+    ssid_labels: HashMap<String, String>,
+
+    /// Polling intervals in seconds used instead of `polling_interval` while connected to these SSIDs
+    #[serde(default)]
+    ssid_polling_intervals: HashMap<String, u64>,
+
+    /// Per-network settings from `[[networks]]` entries, folded into the per-SSID
+    /// tables at startup
+    #[serde(default)]
+    networks: Vec<NetworkConfig>,
+
+    /// Disable the Wi-Fi watcher when no wireless interface is found at startup
+    #[serde(default = "default_wifi_autodisable")]
+    wifi_autodisable: bool,
+
+    /// Milliseconds to wait at startup before the first connectivity check and Wi-Fi scan
+    #[serde(default)]
+    startup_delay_ms: u64,
+
+    /// Network event title ("unknown" or "offline") sent at startup until the first probe completes
+    #[serde(default)]
+    initial_state: Option<String>,
+
+    /// Number of recent probe latencies to average; 0 disables latency reporting
+    #[serde(default)]
+    latency_window: usize,
+
+    /// Report latencies as floats with this many decimals instead of whole milliseconds
+    #[serde(default)]
+    latency_precision: Option<u32>,
+
+    /// Upper bounds in ms of the ranges latency is reported in as "latency_bucket"
+    #[serde(default)]
+    latency_buckets: Vec<u64>,
+
+    /// Report the negotiated link speed of the active connection in Mbps
+    #[serde(default)]
+    include_link_speed: bool,
+
+    /// Report the Wi-Fi standard (e.g. "802.11ax") of the connected network in Wi-Fi events
+    #[serde(default)]
+    include_phy_mode: bool,
+
+    /// Send the "No Wi-Fi networks" state once instead of on every scan while it lasts
+    #[serde(default)]
+    suppress_repeated_empty_wifi: bool,
+
+    /// List networks that don't broadcast their SSID as "<hidden>" instead of leaving them out
+    #[serde(default)]
+    report_hidden_ssids: bool,
+
+    /// Wi-Fi events sent per scan: "aggregate" (one for all networks) or "per_network"
+    #[serde(default = "default_wifi_event_granularity")]
+    wifi_event_granularity: String,
+
+    /// Report which tool the Wi-Fi scan was done with in Wi-Fi events
+    #[serde(default)]
+    include_scan_tool: bool,
+
+    /// Report the IPv4 and IPv6 addresses of the interface that carried the probe
+    #[serde(default)]
+    include_ip_addresses: bool,
+
+    /// Report the IPv4 subnet (CIDR) of the interface that carried the probe
+    #[serde(default)]
+    include_subnet: bool,
+
+    /// Add the hostname and operating system to every network and Wi-Fi event
+    #[serde(default)]
+    include_host_info: bool,
+
+    /// Add a random ID, generated once per run, to every event
+    #[serde(default)]
+    include_session_id: bool,
+
+    /// Report whether airplane mode is on, where it can be detected
+    #[serde(default)]
+    include_airplane_mode: bool,
+
+    /// Report the DNS servers the system is configured to use
+    #[serde(default)]
+    include_dns_servers: bool,
+
+    /// Report whether the probe went over a cellular, Wi-Fi, VPN or wired interface
+    #[serde(default)]
+    include_connection_type: bool,
+
+    /// Report whether the probe went over a Wi-Fi interface
+    #[serde(default)]
+    include_is_wifi: bool,
+
+    /// Report the gateway of the default route
+    #[serde(default)]
+    include_gateway: bool,
+
+    /// Repeat the event timestamp as an RFC 3339 "timestamp" string in the event data
+    #[serde(default)]
+    embed_timestamp: bool,
+
+    /// Use the time since the previous heartbeat as event duration instead of the interval
+    #[serde(default)]
+    accurate_durations: bool,
+
+    /// Probe a connectivity target on ports 53 and 443 and report "degraded" when only one works
+    #[serde(default)]
+    report_degraded: bool,
+
+    /// Track how often each target responds and probe the most reliable ones first
+    #[serde(default)]
+    prioritize_reliable_targets: bool,
+
+    /// Probe every target each poll and report the fraction that was reachable
+    #[serde(default)]
+    include_quality: bool,
+
+    /// Fraction of targets (0.0-1.0) that must be reachable to count as online;
+    /// unset means any single target
+    #[serde(default)]
+    quality_online_threshold: Option<f64>,
+
+    /// Report the fraction of time the network was online since the watcher started
+    #[serde(default)]
+    include_uptime_stats: bool,
+
+    /// Report how long the previous online/offline state lasted on the event where it changes
+    #[serde(default)]
+    include_previous_state_duration: bool,
+
+    /// Report how many polls in a row the online/offline state has held
+    #[serde(default)]
+    include_stability_counter: bool,
+
+    /// Report how many loop iterations took longer than their interval
+    #[serde(default)]
+    include_timing_stats: bool,
+
+    /// Local time window such as "08:00-22:00" outside of which nothing is polled or sent
+    #[serde(default)]
+    active_hours: Option<String>,
+
+    /// Template for the network bucket ID, e.g. "net_{hostname}_{os}"
+    #[serde(default)]
+    network_bucket_id: Option<String>,
+
+    /// Template for the Wi-Fi bucket ID, e.g. "wifi_{hostname}"
+    #[serde(default)]
+    wifi_bucket_id: Option<String>,
+
+    /// Suffix appended to the network and Wi-Fi bucket IDs on Saturdays and Sundays (local time)
+    #[serde(default)]
+    weekend_bucket_suffix: Option<String>,
+
+    /// Seconds after startup during which state changes don't trigger notifications,
+    /// hooks or transition events
+    #[serde(default)]
+    transition_warmup_seconds: u64,
+
+    /// StatsD server (`host:port`) to send metrics to over UDP every poll
+    #[serde(default)]
+    statsd_addr: Option<String>,
+
+    /// Show a desktop notification when going offline or back online (needs the `notify` feature)
+    #[serde(default)]
+    notify_on_state_change: bool,
+
+    /// Command (with arguments) run when the network comes back online
+    #[serde(default)]
+    on_online_command: Option<String>,
+
+    /// Command (with arguments) run when the network goes offline
+    #[serde(default)]
+    on_offline_command: Option<String>,
+
+    /// Fixed key/value pairs added to the data of every network and Wi-Fi event
+    #[serde(default)]
+    static_tags: HashMap<String, String>,
+
+    /// Names to use instead of the built-in network and Wi-Fi event data field names
+    #[serde(default)]
+    field_names: HashMap<String, String>,
+
+    /// How network events are sent: "heartbeat" every polling interval, or "transitions" only
+    #[serde(default = "default_event_mode")]
+    event_mode: String,
+
+    /// Outputs every event is sent to: "aw" (aw-server), "file-jsonl" and/or "mqtt"
+    #[serde(default = "default_outputs")]
+    outputs: Vec<String>,
+
+    /// File the "file-jsonl" output appends to; defaults to events.jsonl in the data directory
+    #[serde(default)]
+    jsonl_path: Option<String>,
+
+    /// MQTT broker (`host` or `host:port`) the "mqtt" output publishes to
+    #[serde(default)]
+    mqtt_broker: Option<String>,
+
+    /// MQTT topic events are published to
+    #[serde(default = "default_mqtt_topic")]
+    mqtt_topic: String,
+
+    /// Path prefix aw-server is served under behind a reverse proxy, e.g. "/activitywatch"
+    #[serde(default)]
+    server_base_path: Option<String>,
+}
+
+/// Settings for one Wi-Fi network, from a `[[networks]]` entry
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+struct NetworkConfig {
+    /// SSID the entry applies to, matched case-insensitively
+    ssid: String,
+
+    /// Shown as the Wi-Fi event title instead of the SSID
+    #[serde(default)]
+    label: Option<String>,
+
+    /// Polling interval in seconds used instead of `polling_interval` while connected
+    #[serde(default)]
+    polling_interval: Option<u64>,
+
+    /// Whether the network is named in Wi-Fi events
+    #[serde(default = "default_tracked")]
+    tracked: bool,
+}
+
+fn default_tracked() -> bool {
+    true
 }
 
 fn default_polling_interval() -> u64 {
@@ -35,26 +471,336 @@ fn default_wifi_scan_interval() -> u64 {
     300 // 5 minutes
 }
 
-impl AppConfig {
-    fn new() -> Result<Self, ConfigError> {
-        let default_config = Self {
+fn default_create_buckets() -> bool {
+    true
+}
+
+fn default_retry_schedule_ms() -> Vec<u64> {
+    vec![500, 1000, 2000]
+}
+
+fn default_max_heartbeat_gap_seconds() -> u64 {
+    300
+}
+
+fn default_connectivity_mode() -> String {
+    CONNECTIVITY_MODE_TCP.to_string()
+}
+
+fn default_connect_timeout_ms() -> u64 {
+    PROBE_TIMEOUT.as_millis() as u64
+}
+
+fn default_probe_port() -> u16 {
+    53
+}
+
+fn default_linux_wifi_backends() -> Vec<String> {
+    LINUX_WIFI_BACKENDS
+        .iter()
+        .map(|backend| backend.to_string())
+        .collect()
+}
+
+fn default_wifi_autodisable() -> bool {
+    true
+}
+
+fn default_event_mode() -> String {
+    EVENT_MODE_HEARTBEAT.to_string()
+}
+
+fn default_wifi_event_granularity() -> String {
+    WIFI_GRANULARITY_AGGREGATE.to_string()
+}
+
+fn default_outputs() -> Vec<String> {
+    vec![OUTPUT_AW.to_string()]
+}
+
+fn default_mqtt_topic() -> String {
+    "aw-watcher-network/events".to_string()
+}
+
+fn default_enable_watcher() -> bool {
+    true
+}
+
+/// Reliable DNS servers to check connectivity against when no custom targets are configured
+const DEFAULT_PROBE_HOSTS: [&str; 3] = [
+    "1.1.1.1", // Cloudflare DNS
+    "8.8.8.8", // Google DNS
+    "9.9.9.9", // Quad9 DNS
+];
+
+/// IPv6 DNS servers used for the optional IPv6 connectivity check
+const DEFAULT_IPV6_PROBE_HOSTS: [&str; 3] = [
+    "[2606:4700:4700::1111]", // Cloudflare DNS
+    "[2001:4860:4860::8888]", // Google DNS
+    "[2620:fe::fe]",          // Quad9 DNS
+];
+
+/// Environment variable that enables test mode when set to "1"
+const TEST_FAST_ENV: &str = "AW_NETWORK_TEST_FAST";
+
+/// Interval every loop waits for in test mode
+const TEST_FAST_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Resolver shared by all probes, set up at startup with the configured cache TTL
+static RESOLVER: OnceLock<Resolver> = OnceLock::new();
+
+/// Timeout of probe targets without their own, set up at startup from connect_timeout_ms
+static CONNECT_TIMEOUT: OnceLock<Duration> = OnceLock::new();
+
+/// ICMP sockets for "ping" connectivity mode, set up at startup when permitted
+static PING_CONNECTOR: OnceLock<PingConnector> = OnceLock::new();
+
+/// Fields added to events with include_host_info, set up at startup
+static HOST_INFO: OnceLock<Map<String, Value>> = OnceLock::new();
+
+/// ID of this run added to events with include_session_id, set up at startup
+static SESSION_ID: OnceLock<String> = OnceLock::new();
+
+/// Whether test mode is enabled; only ever set at startup
+static TEST_FAST: AtomicBool = AtomicBool::new(false);
+
+/// When the watcher shuts down, if started with `--duration`; only ever set at startup
+static DEADLINE: OnceLock<Instant> = OnceLock::new();
+
+/// Wi-Fi scanning tools supported on Linux, in the default order they are tried
+const LINUX_WIFI_BACKENDS: [&str; 3] = ["nmcli", "iwlist", "iw"];
+
+/// Linux Wi-Fi scanning tools that need root to scan
+#[cfg(target_os = "linux")]
+const LINUX_PRIVILEGED_WIFI_BACKENDS: [&str; 2] = ["iwlist", "iw"];
+
+/// Data fields of network and Wi-Fi events that can be renamed with `field_names`
+const EVENT_FIELDS: [&str; 39] = [
+    "title",
+    "latency_ms",
+    "avg_latency_ms",
+    "latency_bucket",
+    "quality",
+    "primary_reachable",
+    "lan_reachable",
+    "ipv6",
+    "ssid",
+    "online_ratio",
+    "previous_state_duration_seconds",
+    "consecutive_same_state",
+    "overrun_total",
+    "is_default_route",
+    "ipv4_addr",
+    "ipv6_addr",
+    "subnet",
+    "airplane_mode",
+    "dns_servers",
+    "connection_type",
+    "is_wifi",
+    "gateway",
+    "link_speed_mbps",
+    "phy_mode",
+    "degraded_reason",
+    "script_output",
+    "ssids",
+    "raw_ssid",
+    "connected",
+    "signal",
+    "channel",
+    "security",
+    "scan_duration_ms",
+    "scan_tool",
+    "timestamp",
+    "host",
+    "os",
+    "os_version",
+    "session_id",
+];
+
+/// Connectivity mode that probes the connectivity targets over TCP
+const CONNECTIVITY_MODE_TCP: &str = "tcp";
+
+/// Connectivity mode that sends an ICMP echo request to the targets
+const CONNECTIVITY_MODE_PING: &str = "ping";
+
+/// Connectivity mode that runs an external script
+const CONNECTIVITY_MODE_SCRIPT: &str = "script";
+
+/// Connected SSID standing in for networks with `tracked = false`
+const UNTRACKED_SSID: &str = "<untracked>";
+
+/// Scanned SSID standing in for networks that don't broadcast their name
+const HIDDEN_SSID: &str = "<hidden>";
+
+/// Titles accepted for initial_state
+const INITIAL_STATES: [&str; 2] = ["unknown", "offline"];
+
+/// Event mode that sends a heartbeat every polling interval
+const EVENT_MODE_HEARTBEAT: &str = "heartbeat";
+
+/// Event mode that sends an event per state, once the state ends
+const EVENT_MODE_TRANSITIONS: &str = "transitions";
+
+/// Wi-Fi granularity sending one event per scan, listing all networks
+const WIFI_GRANULARITY_AGGREGATE: &str = "aggregate";
+
+/// Wi-Fi granularity sending one heartbeat per visible network on each scan
+const WIFI_GRANULARITY_PER_NETWORK: &str = "per_network";
+
+/// Output name for sending events to aw-server
+const OUTPUT_AW: &str = "aw";
+
+/// Output name for appending events to a JSON Lines file
+const OUTPUT_FILE_JSONL: &str = "file-jsonl";
+
+/// Output name for publishing events to an MQTT broker
+const OUTPUT_MQTT: &str = "mqtt";
+
+/// Port of mqtt_broker when it doesn't name one
+const MQTT_DEFAULT_PORT: u16 = 1883;
+
+/// Wait before reconnecting after the MQTT connection failed
+const MQTT_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Wait before trying aw-server again after it first couldn't be reached
+const SERVER_BACKOFF_MIN: Duration = Duration::from_secs(5);
+
+/// Longest wait between attempts while aw-server can't be reached
+const SERVER_BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+/// Minimum time between diagnostics events for the same component and level
+const DIAGNOSTICS_MIN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Longest accepted polling_interval and wifi_scan_interval, in seconds (one day)
+const MAX_INTERVAL_SECONDS: u64 = 24 * 60 * 60;
+
+/// Number of recent probes of each target its reliability is based on
+const TARGET_HISTORY_LEN: usize = 20;
+
+/// With accurate_durations, longest event duration in intervals. A longer gap
+/// since the previous heartbeat wasn't observed, e.g. the computer slept
+const MAX_DURATION_INTERVALS: u32 = 4;
+
+/// Number of consecutive offline polls after which the network counts as persistently offline
+const SUSTAINED_OFFLINE_POLLS: u32 = 3;
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        Self {
             polling_interval: default_polling_interval(),
             wifi_scan_interval: default_wifi_scan_interval(),
-        };
+            wifi_scan_interval_docked: None,
+            check_ipv6: false,
+            linux_scan_command: None,
+            linux_wifi_backends: default_linux_wifi_backends(),
+            require_unprivileged_scan: false,
+            macos_scan_command: None,
+            windows_scan_command: None,
+            offline_polling_interval: None,
+            create_buckets: default_create_buckets(),
+            include_ssid_in_network_event: false,
+            probe_interval: None,
+            align_to_clock: false,
+            include_default_route: false,
+            emit_diagnostics: false,
+            emit_wifi_transitions: false,
+            track_proxy_changes: false,
+            dedup_network_events: false,
+            max_heartbeat_gap_seconds: default_max_heartbeat_gap_seconds(),
+            keepalive_interval_seconds: 0,
+            retry_schedule_ms: default_retry_schedule_ms(),
+            retry_schedule_repeat: false,
+            default_probe_port: default_probe_port(),
+            connect_timeout_ms: default_connect_timeout_ms(),
+            connectivity_mode: default_connectivity_mode(),
+            connectivity_script: None,
+            connectivity_targets: Vec::new(),
+            targets_per_poll: None,
+            primary_target: None,
+            dns_cache_ttl_seconds: 0,
+            lan_probe_target: None,
+            enable_network_watcher: default_enable_watcher(),
+            enable_wifi_watcher: default_enable_watcher(),
+            ssid_labels: HashMap::new(),
+            ssid_polling_intervals: HashMap::new(),
+            networks: Vec::new(),
+            wifi_autodisable: default_wifi_autodisable(),
+            startup_delay_ms: 0,
+            initial_state: None,
+            latency_window: 0,
+            latency_precision: None,
+            latency_buckets: Vec::new(),
+            include_link_speed: false,
+            include_phy_mode: false,
+            include_scan_tool: false,
+            suppress_repeated_empty_wifi: false,
+            report_hidden_ssids: false,
+            wifi_event_granularity: default_wifi_event_granularity(),
+            include_ip_addresses: false,
+            include_subnet: false,
+            include_host_info: false,
+            include_session_id: false,
+            include_airplane_mode: false,
+            include_dns_servers: false,
+            include_connection_type: false,
+            include_is_wifi: false,
+            include_gateway: false,
+            embed_timestamp: false,
+            accurate_durations: false,
+            report_degraded: false,
+            prioritize_reliable_targets: false,
+            include_quality: false,
+            quality_online_threshold: None,
+            include_uptime_stats: false,
+            include_previous_state_duration: false,
+            include_stability_counter: false,
+            include_timing_stats: false,
+            active_hours: None,
+            network_bucket_id: None,
+            wifi_bucket_id: None,
+            weekend_bucket_suffix: None,
+            notify_on_state_change: false,
+            on_online_command: None,
+            on_offline_command: None,
+            transition_warmup_seconds: 0,
+            statsd_addr: None,
+            static_tags: HashMap::new(),
+            field_names: HashMap::new(),
+            event_mode: default_event_mode(),
+            outputs: default_outputs(),
+            jsonl_path: None,
+            mqtt_broker: None,
+            mqtt_topic: default_mqtt_topic(),
+            server_base_path: None,
+        }
+    }
+}
 
-        // Get the configuration directory
-        let config_path = if let Some(config_dir) = config_dir() {
-            let aw_config_dir = config_dir.join("activitywatch").join("aw-watcher-network");
+impl AppConfig {
+    fn new(read_only: bool) -> Result<Self, ConfigError> {
+        Self::load(watcher_config_dir().as_deref(), read_only)
+    }
 
-            // Create the directory if it doesn't exist
-            create_dir_all(&aw_config_dir).ok();
+    /// Load config.toml and the config.d/ drop-ins from the configuration
+    /// directory, first creating config.toml with the defaults unless `read_only`
+    fn load(aw_config_dir: Option<&Path>, read_only: bool) -> Result<Self, ConfigError> {
+        let default_config = Self::default();
 
+        let config_path = if let Some(aw_config_dir) = aw_config_dir {
             let config_file = aw_config_dir.join("config.toml");
 
             // If the config file doesn't exist, create it with default values
-            if !config_file.exists() {
+            if !read_only && !config_file.exists() {
                 let default_config_str = toml::to_string_pretty(&default_config).unwrap();
-                write(&config_file, default_config_str).ok();
+                if let Err(e) = create_dir_all(aw_config_dir)
+                    .and_then(|_| write(&config_file, default_config_str))
+                {
+                    eprintln!(
+                        "Warning: could not create {} ({}), using the default configuration. Pass --read-only-config to skip creating it",
+                        config_file.display(),
+                        e
+                    );
+                }
             }
 
             Some(config_file)
@@ -66,8 +812,15 @@ impl AppConfig {
         let mut builder = Config::builder();
 
         // Add the config file if it exists
-        if let Some(path) = config_path {
-            if path.exists() {
+        if let Some(path) = config_path
+            && path.exists()
+        {
+            builder = builder.add_source(File::from(path));
+        }
+
+        // Add drop-in overrides from config.d/, in sorted order so later files take precedence
+        if let Some(aw_config_dir) = aw_config_dir {
+            for path in drop_in_config_files(&aw_config_dir.join("config.d")) {
                 builder = builder.add_source(File::from(path));
             }
         }
@@ -78,204 +831,4511 @@ impl AppConfig {
             Err(_) => Ok(default_config),
         }
     }
-}
 
-fn main() {
-    // Load configuration
-    let config = match AppConfig::new() {
-        Ok(config) => config,
-        Err(e) => {
-            eprintln!("Error loading configuration: {}", e);
+    /// The user-configured Wi-Fi scan command for the current platform, if any
+    fn scan_command(&self) -> Option<&str> {
+        let command = if cfg!(target_os = "macos") {
+            self.macos_scan_command.as_deref()
+        } else if cfg!(target_os = "windows") {
+            self.windows_scan_command.as_deref()
+        } else {
+            self.linux_scan_command.as_deref()
+        };
+
+        command.filter(|command| !command.trim().is_empty())
+    }
+
+    /// Parsed `active_hours`, if set
+    fn active_hours(&self) -> Result<Option<ActiveHours>, String> {
+        self.active_hours
+            .as_deref()
+            .map(ActiveHours::parse)
+            .transpose()
+    }
 
-            AppConfig {
-                polling_interval: default_polling_interval(),
-                wifi_scan_interval: default_wifi_scan_interval(),
+    /// Bucket to send events to right now: `bucket_id`, with the weekend suffix
+    /// appended on Saturdays and Sundays
+    fn bucket_id_for_today(&self, bucket_id: &str) -> String {
+        match &self.weekend_bucket_suffix {
+            Some(suffix) if matches!(Local::now().weekday(), Weekday::Sat | Weekday::Sun) => {
+                format!("{}{}", bucket_id, suffix)
             }
+            _ => bucket_id.to_string(),
         }
-    };
+    }
 
-    let polling_interval = config.polling_interval;
+    /// Fold the `[[networks]]` entries into `ssid_labels` and `ssid_polling_intervals`.
+    /// An SSID can only be configured once across them, and every polling interval
+    /// must be between 1 and `MAX_INTERVAL_SECONDS`
+    fn apply_networks(&mut self) -> Result<(), String> {
+        let mut table_intervals: Vec<(&String, &u64)> =
+            self.ssid_polling_intervals.iter().collect();
+        table_intervals.sort();
+        if let Some((ssid, interval)) = table_intervals
+            .into_iter()
+            .find(|(_, interval)| **interval == 0 || **interval > MAX_INTERVAL_SECONDS)
+        {
+            return Err(format!(
+                "Invalid polling_interval of {}s for SSID '{}' in [ssid_polling_intervals] (must be between 1 and {})",
+                interval, ssid, MAX_INTERVAL_SECONDS
+            ));
+        }
 
-    // Get hostname and create bucket ID with hostname appended
-    let hostname = match get_hostname() {
-        Ok(name) => name.to_string_lossy().into_owned(),
-        Err(_) => "unknown-host".to_string(),
-    };
+        for (index, network) in self.networks.iter().enumerate() {
+            let ssid = &network.ssid;
+            if self.networks[..index]
+                .iter()
+                .any(|other| other.ssid.to_lowercase() == ssid.to_lowercase())
+            {
+                return Err(format!(
+                    "SSID '{}' is in more than one [[networks]] entry",
+                    ssid
+                ));
+            }
 
-    let bucket_id = format!("aw-watcher-network_{}", hostname);
-    let wifi_bucket_id = format!("aw-watcher-wifi_{}", hostname);
-    let event_type = "network-status";
-    let wifi_event_type = "wifi-status";
+            if !network.tracked && (network.label.is_some() || network.polling_interval.is_some()) {
+                return Err(format!(
+                    "[[networks]] entry '{}' isn't tracked, so it can't have a label or polling_interval",
+                    ssid
+                ));
+            }
 
-    println!(
-        "Starting aw-watcher-network-rs with polling interval of {} seconds",
-        polling_interval
-    );
-    println!(
-        "Wi-Fi SSID scanning interval: {} seconds",
-        config.wifi_scan_interval
-    );
-    println!("Using bucket ID: {}", bucket_id);
-    println!("Using Wi-Fi bucket ID: {}", wifi_bucket_id);
+            if let Some(label) = &network.label {
+                if lookup_ssid(&self.ssid_labels, ssid).is_some() {
+                    return Err(format!(
+                        "SSID '{}' has a label in both [[networks]] and [ssid_labels]",
+                        ssid
+                    ));
+                }
+                self.ssid_labels.insert(ssid.clone(), label.clone());
+            }
+
+            if let Some(interval) = network.polling_interval {
+                if interval == 0 || interval > MAX_INTERVAL_SECONDS {
+                    return Err(format!(
+                        "Invalid polling_interval of {}s for [[networks]] entry '{}' (must be between 1 and {})",
+                        interval, ssid, MAX_INTERVAL_SECONDS
+                    ));
+                }
+                if lookup_ssid(&self.ssid_polling_intervals, ssid).is_some() {
+                    return Err(format!(
+                        "SSID '{}' has a polling interval in both [[networks]] and [ssid_polling_intervals]",
+                        ssid
+                    ));
+                }
+                self.ssid_polling_intervals.insert(ssid.clone(), interval);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether an SSID may be named in events, i.e. it isn't an untracked `[[networks]]` entry
+    fn ssid_tracked(&self, ssid: &str) -> bool {
+        let ssid = ssid.to_lowercase();
+        !self
+            .networks
+            .iter()
+            .any(|network| !network.tracked && network.ssid.to_lowercase() == ssid)
+    }
+
+    /// Label configured for an SSID
+    fn ssid_label(&self, ssid: &str) -> Option<&str> {
+        lookup_ssid(&self.ssid_labels, ssid).map(String::as_str)
+    }
+
+    /// Polling interval for the connected network: its override if there is one,
+    /// otherwise `polling_interval`
+    fn polling_interval_for(&self, connected_ssid: Option<&str>) -> u64 {
+        connected_ssid
+            .and_then(|ssid| lookup_ssid(&self.ssid_polling_intervals, ssid))
+            .copied()
+            .unwrap_or(self.polling_interval)
+    }
+
+    /// Check that `field_names` only renames known fields and gives every field a distinct name
+    fn validate_field_names(&self) -> Result<(), String> {
+        if let Some(field) = self
+            .field_names
+            .keys()
+            .find(|field| !EVENT_FIELDS.contains(&field.as_str()))
+        {
+            return Err(format!(
+                "Unknown field '{}' in field_names, expected one of: {}",
+                field,
+                EVENT_FIELDS.join(", ")
+            ));
+        }
+
+        let mut names: HashMap<&str, &str> = HashMap::new();
+        for field in EVENT_FIELDS {
+            let name = self.field_name(field);
+            if let Some(other) = names.insert(name, field) {
+                return Err(format!(
+                    "Fields '{}' and '{}' would both be named '{}', check field_names",
+                    other, field, name
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Name an event data field is sent as
+    fn field_name<'a>(&'a self, field: &'a str) -> &'a str {
+        self.field_names.get(field).map_or(field, String::as_str)
+    }
+
+    /// Apply `field_names` to the data of a network or Wi-Fi event
+    fn rename_fields(&self, data: Map<String, Value>) -> Map<String, Value> {
+        if self.field_names.is_empty() {
+            return data;
+        }
+
+        data.into_iter()
+            .map(|(field, value)| (self.field_name(&field).to_string(), value))
+            .collect()
+    }
+
+    /// Static tags named like one of the watcher's own event fields, which they never replace
+    fn reserved_static_tags(&self) -> Vec<&str> {
+        self.static_tags
+            .keys()
+            .map(String::as_str)
+            .filter(|tag| {
+                EVENT_FIELDS
+                    .iter()
+                    .any(|field| self.field_name(field) == *tag)
+            })
+            .collect()
+    }
+
+    /// Add `static_tags` to the data of a network or Wi-Fi event, keeping any
+    /// field the watcher already set
+    fn add_static_tags(&self, mut data: Map<String, Value>) -> Map<String, Value> {
+        for (tag, value) in &self.static_tags {
+            data.entry(tag.clone())
+                .or_insert_with(|| Value::String(value.clone()));
+        }
+        data
+    }
+
+    /// Configured Linux Wi-Fi backends that aren't supported
+    fn unknown_linux_wifi_backends(&self) -> Vec<&str> {
+        self.linux_wifi_backends
+            .iter()
+            .map(String::as_str)
+            .filter(|backend| !LINUX_WIFI_BACKENDS.contains(backend))
+            .collect()
+    }
+
+    /// Configured outputs that aren't known output names
+    fn unknown_outputs(&self) -> Vec<&str> {
+        self.outputs
+            .iter()
+            .map(String::as_str)
+            .filter(|output| ![OUTPUT_AW, OUTPUT_FILE_JSONL, OUTPUT_MQTT].contains(output))
+            .collect()
+    }
+
+    /// Whether events are sent to the given output
+    fn has_output(&self, output: &str) -> bool {
+        self.outputs.iter().any(|configured| configured == output)
+    }
+
+    /// File the "file-jsonl" output appends to
+    fn jsonl_path(&self) -> Option<PathBuf> {
+        match &self.jsonl_path {
+            Some(path) => Some(PathBuf::from(path)),
+            None => data_dir().map(|dir| {
+                dir.join("activitywatch")
+                    .join("aw-watcher-network")
+                    .join("events.jsonl")
+            }),
+        }
+    }
+
+    /// server_base_path as "/segment/...", or `None` when it is unset or empty
+    fn server_base_path(&self) -> Result<Option<String>, String> {
+        self.server_base_path
+            .as_deref()
+            .map_or(Ok(None), normalize_base_path)
+    }
+
+    /// Host and port of mqtt_broker, or `None` if it isn't set or the port is invalid
+    fn mqtt_address(&self) -> Option<(String, u16)> {
+        let broker = self.mqtt_broker.as_deref()?;
+        match broker.rsplit_once(':') {
+            Some((host, port)) => Some((host.to_string(), port.parse().ok()?)),
+            None => Some((broker.to_string(), MQTT_DEFAULT_PORT)),
+        }
+    }
+
+    /// Replace a zero or longer than `MAX_INTERVAL_SECONDS` polling_interval or
+    /// wifi_scan_interval with its default. Returns each (field, value, default)
+    /// that was replaced
+    fn reset_invalid_intervals(&mut self) -> Vec<(&'static str, u64, u64)> {
+        let mut replaced = Vec::new();
+        let intervals = [
+            (
+                "polling_interval",
+                &mut self.polling_interval,
+                default_polling_interval(),
+            ),
+            (
+                "wifi_scan_interval",
+                &mut self.wifi_scan_interval,
+                default_wifi_scan_interval(),
+            ),
+        ];
+        for (field, interval, default) in intervals {
+            if *interval == 0 || *interval > MAX_INTERVAL_SECONDS {
+                replaced.push((field, *interval, default));
+                *interval = default;
+            }
+        }
+        replaced
+    }
+
+    /// Rewrite unbracketed IPv6 `address:port` targets into the `[address]:port`
+    /// form they resolve with. Returns each (original, corrected) pair
+    fn bracket_ipv6_targets(&mut self) -> Vec<(String, String)> {
+        let mut corrected = Vec::new();
+        let targets = self
+            .connectivity_targets
+            .iter_mut()
+            .chain(self.primary_target.iter_mut())
+            .chain(self.lan_probe_target.iter_mut());
+        for target in targets {
+            if let Some(bracketed) = bracketed_ipv6_target(target) {
+                corrected.push((target.clone(), bracketed.clone()));
+                *target = bracketed;
+            }
+        }
+        corrected
+    }
+
+    /// First error in the timeout suffix of a probe target, if any
+    fn validate_target_timeouts(&self) -> Result<(), String> {
+        self.connectivity_targets
+            .iter()
+            .chain(&self.primary_target)
+            .chain(&self.lan_probe_target)
+            .try_for_each(|target| parse_target(target).map(|_| ()))
+    }
+
+    /// Targets to probe for connectivity: the custom list if set, otherwise the
+    /// built-in DNS servers on `default_probe_port`
+    fn connectivity_targets(&self) -> Vec<String> {
+        if !self.connectivity_targets.is_empty() {
+            return self.connectivity_targets.clone();
+        }
+
+        DEFAULT_PROBE_HOSTS
+            .iter()
+            .map(|host| format!("{}:{}", host, self.default_probe_port))
+            .collect()
+    }
+}
+
+/// The `[address]:port` form of a target written as an unbracketed IPv6 literal
+/// followed by a port, e.g. `2606:4700:4700::1111:53`. The last group is taken
+/// as the port, since `to_socket_addrs` can't tell the two apart. An `@timeout`
+/// suffix is split off with `parse_target` and kept as it is
+fn bracketed_ipv6_target(target: &str) -> Option<String> {
+    let (host_port, _) = parse_target(target).ok()?;
+    let timeout = &target[host_port.len()..];
+    if host_port.starts_with('[') || host_port.matches(':').count() < 2 {
+        return None;
+    }
+    let (address, port) = host_port.rsplit_once(':')?;
+    address.parse::<Ipv6Addr>().ok()?;
+    port.parse::<u16>().ok()?;
+    Some(format!("[{}]:{}{}", address, port, timeout))
+}
+
+/// Look up an SSID in a config table. The config loader lowercases keys, so match case-insensitively
+fn lookup_ssid<'a, T>(table: &'a HashMap<String, T>, ssid: &str) -> Option<&'a T> {
+    let ssid = ssid.to_lowercase();
+    table
+        .iter()
+        .find(|(key, _)| key.to_lowercase() == ssid)
+        .map(|(_, value)| value)
+}
+
+/// Replace `{name}` placeholders in a bucket ID template. Unknown placeholders
+/// and unmatched braces are errors rather than being left in the bucket ID
+fn expand_bucket_template(template: &str, values: &[(&str, &str)]) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        if rest[..start].contains('}') {
+            return Err(format!("Unmatched '}}' in bucket ID '{}'", template));
+        }
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("Unclosed placeholder in bucket ID '{}'", template))?;
+        let name = &rest[start + 1..start + end];
+        let value = values
+            .iter()
+            .find(|(placeholder, _)| *placeholder == name)
+            .map(|(_, value)| *value)
+            .ok_or_else(|| {
+                format!(
+                    "Unknown placeholder {{{}}} in bucket ID '{}'",
+                    name, template
+                )
+            })?;
+        expanded.push_str(value);
+        rest = &rest[start + end + 1..];
+    }
+
+    if rest.contains('}') {
+        return Err(format!("Unmatched '}}' in bucket ID '{}'", template));
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Daily local time window during which the watchers poll
+#[derive(Debug, Clone, Copy)]
+struct ActiveHours {
+    /// Start of the window, in seconds since midnight
+    start: u32,
+    /// End of the window (exclusive), in seconds since midnight
+    end: u32,
+}
+
+impl ActiveHours {
+    /// Parse a window such as "08:00-22:00"; the end may be earlier than the start
+    /// for windows that wrap past midnight
+    fn parse(window: &str) -> Result<Self, String> {
+        let parse_time = |time: &str| -> Result<u32, String> {
+            let (hours, minutes) = time
+                .trim()
+                .split_once(':')
+                .ok_or_else(|| format!("Invalid time '{}', expected HH:MM", time.trim()))?;
+            match (hours.parse::<u32>(), minutes.parse::<u32>()) {
+                (Ok(hours), Ok(minutes)) if hours < 24 && minutes < 60 => {
+                    Ok(hours * 3600 + minutes * 60)
+                }
+                _ => Err(format!("Invalid time '{}', expected HH:MM", time.trim())),
+            }
+        };
+
+        let (start, end) = window
+            .split_once('-')
+            .ok_or_else(|| format!("Invalid active hours '{}', expected HH:MM-HH:MM", window))?;
+        let (start, end) = (parse_time(start)?, parse_time(end)?);
+
+        if start == end {
+            return Err(format!(
+                "Invalid active hours '{}', start and end must differ",
+                window
+            ));
+        }
+
+        Ok(Self { start, end })
+    }
+
+    /// How long until the window next opens, or `None` if it is open now
+    fn time_until_active(&self) -> Option<Duration> {
+        let now = Local::now().num_seconds_from_midnight();
+
+        let active = if self.start < self.end {
+            self.start <= now && now < self.end
+        } else {
+            now >= self.start || now < self.end
+        };
+
+        if active {
+            None
+        } else {
+            let wait = (self.start as i64 - now as i64).rem_euclid(24 * 3600);
+            Some(Duration::from_secs(wait as u64))
+        }
+    }
+}
+
+/// Sleep until the active hours window opens, returning whether any sleeping was needed
+fn wait_for_active_hours(active_hours: Option<ActiveHours>, component: &str) -> bool {
+    let Some(wait) = active_hours.and_then(|active_hours| active_hours.time_until_active()) else {
+        return false;
+    };
+
+    println!(
+        "Outside active hours, pausing the {} watcher for {} minutes",
+        component,
+        wait.as_secs().div_ceil(60)
+    );
+    sleep_until_deadline(wait);
+    true
+}
+
+/// Latest Wi-Fi scan results, shared between the Wi-Fi thread and the main loop
+#[derive(Debug, Default)]
+struct WifiState {
+    /// SSID of the currently connected network, if any
+    connected_ssid: Option<String>,
+    /// All SSIDs seen in the latest scan
+    ssids: Vec<String>,
+}
+
+/// Normalize a base path to "/segment/..." without a trailing slash, or `None`
+/// if it has no segments. Segments may only hold unreserved URL characters
+fn normalize_base_path(path: &str) -> Result<Option<String>, String> {
+    let segments: Vec<&str> = path
+        .trim()
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+    for segment in &segments {
+        let unreserved = segment
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-._~".contains(c));
+        if !unreserved || matches!(*segment, "." | "..") {
+            return Err(format!(
+                "Invalid server_base_path '{}', expected a path such as \"/activitywatch\"",
+                path
+            ));
+        }
+    }
+    Ok((!segments.is_empty()).then(|| format!("/{}", segments.join("/"))))
+}
+
+/// Where aw-server is reached, for messages
+fn server_address(base_path: Option<&str>) -> String {
+    format!("localhost:5600{}", base_path.unwrap_or(""))
+}
+
+/// URL of an aw-server API endpoint under a normalized base path
+fn server_url(base_path: &str, endpoint: &str) -> String {
+    format!("http://localhost:5600{}/api/0/{}", base_path, endpoint)
+}
+
+/// Client for an aw-server under a base path. aw-client-rust only builds
+/// http://host:port URLs, so the endpoints the watcher uses are requested directly
+struct PrefixedClient {
+    client: reqwest::blocking::Client,
+    base_path: String,
+    hostname: String,
+}
+
+impl PrefixedClient {
+    fn new(base_path: &str) -> Self {
+        Self {
+            client: reqwest::blocking::Client::new(),
+            base_path: base_path.to_string(),
+            hostname: get_hostname()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|_| "unknown".to_string()),
+        }
+    }
+
+    fn url(&self, endpoint: &str) -> String {
+        server_url(&self.base_path, endpoint)
+    }
+
+    fn get_info(&self) -> Result<Info, reqwest::Error> {
+        self.client
+            .get(self.url("info"))
+            .send()?
+            .error_for_status()?
+            .json()
+    }
+
+    fn get_bucket(&self, bucket_id: &str) -> Result<Bucket, reqwest::Error> {
+        self.client
+            .get(self.url(&format!("buckets/{}", bucket_id)))
+            .send()?
+            .error_for_status()?
+            .json()
+    }
+
+    fn create_bucket_simple(
+        &self,
+        bucket_id: &str,
+        event_type: &str,
+    ) -> Result<(), reqwest::Error> {
+        let mut bucket = Map::new();
+        bucket.insert("id".to_string(), Value::from(bucket_id));
+        bucket.insert("type".to_string(), Value::from(event_type));
+        bucket.insert("client".to_string(), Value::from("aw-watcher-network"));
+        bucket.insert("hostname".to_string(), Value::from(self.hostname.as_str()));
+        self.client
+            .post(self.url(&format!("buckets/{}", bucket_id)))
+            .json(&bucket)
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    fn delete_bucket(&self, bucket_id: &str) -> Result<(), reqwest::Error> {
+        self.client
+            .delete(self.url(&format!("buckets/{}?force=1", bucket_id)))
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    fn get_events(
+        &self,
+        bucket_id: &str,
+        limit: Option<u64>,
+    ) -> Result<Vec<Event>, reqwest::Error> {
+        let endpoint = match limit {
+            Some(limit) => format!("buckets/{}/events?limit={}", bucket_id, limit),
+            None => format!("buckets/{}/events", bucket_id),
+        };
+        self.client
+            .get(self.url(&endpoint))
+            .send()?
+            .error_for_status()?
+            .json()
+    }
+
+    fn insert_events(&self, bucket_id: &str, events: &[Event]) -> Result<(), reqwest::Error> {
+        self.client
+            .post(self.url(&format!("buckets/{}/events", bucket_id)))
+            .json(events)
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    fn heartbeat(
+        &self,
+        bucket_id: &str,
+        event: &Event,
+        pulsetime: f64,
+    ) -> Result<(), reqwest::Error> {
+        self.client
+            .post(self.url(&format!(
+                "buckets/{}/heartbeat?pulsetime={}",
+                bucket_id, pulsetime
+            )))
+            .json(event)
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// aw-server client: aw-client-rust, or a PrefixedClient when server_base_path is set
+enum ServerClient {
+    Direct(AwClient),
+    Prefixed(PrefixedClient),
+}
+
+impl ServerClient {
+    fn new(base_path: Option<&str>) -> Result<Self, String> {
+        match base_path {
+            Some(base_path) => Ok(Self::Prefixed(PrefixedClient::new(base_path))),
+            None => AwClient::new("localhost", 5600, "aw-watcher-network")
+                .map(Self::Direct)
+                .map_err(|e| e.to_string()),
+        }
+    }
+
+    fn get_info(&self) -> Result<Info, reqwest::Error> {
+        match self {
+            Self::Direct(client) => client.get_info(),
+            Self::Prefixed(client) => client.get_info(),
+        }
+    }
+
+    fn get_bucket(&self, bucket_id: &str) -> Result<Bucket, reqwest::Error> {
+        match self {
+            Self::Direct(client) => client.get_bucket(bucket_id),
+            Self::Prefixed(client) => client.get_bucket(bucket_id),
+        }
+    }
+
+    fn create_bucket_simple(
+        &self,
+        bucket_id: &str,
+        event_type: &str,
+    ) -> Result<(), reqwest::Error> {
+        match self {
+            Self::Direct(client) => client.create_bucket_simple(bucket_id, event_type),
+            Self::Prefixed(client) => client.create_bucket_simple(bucket_id, event_type),
+        }
+    }
+
+    fn delete_bucket(&self, bucket_id: &str) -> Result<(), reqwest::Error> {
+        match self {
+            Self::Direct(client) => client.delete_bucket(bucket_id),
+            Self::Prefixed(client) => client.delete_bucket(bucket_id),
+        }
+    }
+
+    fn get_events(
+        &self,
+        bucket_id: &str,
+        limit: Option<u64>,
+    ) -> Result<Vec<Event>, reqwest::Error> {
+        match self {
+            Self::Direct(client) => client.get_events(bucket_id, None, None, limit),
+            Self::Prefixed(client) => client.get_events(bucket_id, limit),
+        }
+    }
+
+    fn insert_event(&self, bucket_id: &str, event: &Event) -> Result<(), reqwest::Error> {
+        match self {
+            Self::Direct(client) => client.insert_event(bucket_id, event),
+            Self::Prefixed(client) => client.insert_events(bucket_id, std::slice::from_ref(event)),
+        }
+    }
+
+    fn insert_events(&self, bucket_id: &str, events: Vec<Event>) -> Result<(), reqwest::Error> {
+        match self {
+            Self::Direct(client) => client.insert_events(bucket_id, events),
+            Self::Prefixed(client) => client.insert_events(bucket_id, &events),
+        }
+    }
+
+    fn heartbeat(
+        &self,
+        bucket_id: &str,
+        event: &Event,
+        pulsetime: f64,
+    ) -> Result<(), reqwest::Error> {
+        match self {
+            Self::Direct(client) => client.heartbeat(bucket_id, event, pulsetime),
+            Self::Prefixed(client) => client.heartbeat(bucket_id, event, pulsetime),
+        }
+    }
+}
+
+/// Create a bucket on aw-server. A bucket that already exists, the usual case
+/// on restart, counts as created
+fn create_bucket(client: &ServerClient, bucket_id: &str, event_type: &str) -> Result<(), String> {
+    try_create_bucket(client, bucket_id, event_type).map_err(|e| e.to_string())
+}
+
+/// Like `create_bucket`, keeping the request error so callers can tell an
+/// unreachable server apart from an error response
+fn try_create_bucket(
+    client: &ServerClient,
+    bucket_id: &str,
+    event_type: &str,
+) -> Result<(), reqwest::Error> {
+    match client.create_bucket_simple(bucket_id, event_type) {
+        Ok(()) => Ok(()),
+        // 304 Not Modified or 409 Conflict, depending on the aw-server version
+        Err(e)
+            if e.status()
+                .is_some_and(|status| [304, 409].contains(&status.as_u16())) =>
+        {
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Delays between attempts at an aw-server request, from retry_schedule_ms
+#[derive(Debug, Clone)]
+struct RetrySchedule {
+    delays: Vec<Duration>,
+    /// Keep using the last delay once the list is used up
+    repeat: bool,
+}
+
+impl RetrySchedule {
+    fn from_config(config: &AppConfig) -> Self {
+        Self {
+            delays: config
+                .retry_schedule_ms
+                .iter()
+                .map(|&millis| Duration::from_millis(millis))
+                .collect(),
+            repeat: config.retry_schedule_repeat,
+        }
+    }
+
+    /// Delay before the given retry (counting from 0), or `None` once the
+    /// schedule is used up
+    fn delay(&self, retry: usize) -> Option<Duration> {
+        match self.delays.get(retry) {
+            Some(delay) => Some(*delay),
+            None if self.repeat => self.delays.last().copied(),
+            None => None,
+        }
+    }
+
+    /// The same delays, giving up once they are used up even if `repeat` is set
+    fn bounded(&self) -> Self {
+        Self {
+            delays: self.delays.clone(),
+            repeat: false,
+        }
+    }
+
+    /// Call `attempt` until it succeeds, the schedule is used up or the
+    /// `--duration` deadline passes, logging each retry
+    fn run<T>(&self, what: &str, attempt: impl FnMut() -> Result<T, String>) -> Result<T, String> {
+        self.run_while(what, || true, attempt)
+    }
+
+    /// Like `run`, but also give up once `retry_due` returns false after a failed attempt
+    fn run_while<T>(
+        &self,
+        what: &str,
+        retry_due: impl Fn() -> bool,
+        mut attempt: impl FnMut() -> Result<T, String>,
+    ) -> Result<T, String> {
+        let mut retry = 0;
+        loop {
+            match attempt() {
+                Ok(value) => return Ok(value),
+                Err(e) => {
+                    let Some(delay) = self.delay(retry).filter(|_| !shutdown_due() && retry_due())
+                    else {
+                        return Err(e);
+                    };
+                    eprintln!(
+                        "{} failed ({}), retrying in {}ms",
+                        what,
+                        e,
+                        delay.as_millis()
+                    );
+                    sleep_until_deadline(delay);
+                    retry += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Where the watcher sends its events. A single sink is shared behind an `Arc`
+/// by the network loop, the Wi-Fi thread and diagnostics
+trait EventSink: Send + Sync {
+    /// Create a bucket the first time it is used
+    fn ensure_bucket(&self, bucket_id: &str, event_type: &str) -> Result<(), String>;
+
+    /// Send a heartbeat, merged with the previous event if within `pulsetime` seconds
+    fn send(&self, bucket_id: &str, event: &Event, pulsetime: f64) -> Result<(), String>;
+
+    /// Insert an event as is
+    fn insert_event(&self, bucket_id: &str, event: &Event) -> Result<(), String>;
+}
+
+/// Sends events to every configured output. A failing output doesn't stop
+/// the others from receiving the event
+struct EventSinks {
+    /// aw-server client, when the "aw" output is enabled. The client isn't Clone,
+    /// so the threads share this one connection
+    client: Option<Mutex<ServerClient>>,
+    /// File events are appended to as JSON lines, when the "file-jsonl" output is enabled
+    jsonl_path: Option<PathBuf>,
+    /// MQTT client and the topic events are published to, when the "mqtt" output is enabled
+    mqtt: Option<(MqttClient, String)>,
+    /// Buckets created on demand so far
+    created_buckets: Mutex<HashSet<String>>,
+    /// Buckets a heartbeat has been sent to, retries included
+    heartbeat_buckets: Mutex<HashSet<String>>,
+    /// Delays between attempts at the first heartbeat to a bucket
+    retry_schedule: RetrySchedule,
+    /// Whether the last aw-server request got an answer. While it didn't, events
+    /// for aw-server are dropped until the retry time, whichever loop sends them
+    server_reachable: AtomicBool,
+    /// When to try aw-server again while it can't be reached, and the backoff that led there
+    server_retry: Mutex<(Instant, Duration)>,
+}
+
+impl EventSinks {
+    /// Set up the configured outputs
+    fn new(config: &AppConfig) -> Result<Self, String> {
+        let client = if config.has_output(OUTPUT_AW) {
+            // Validated at startup
+            let base_path = config.server_base_path()?;
+            let client = ServerClient::new(base_path.as_deref()).map_err(|e| {
+                format!(
+                    "Failed to create aw-server client for {}: {}",
+                    server_address(base_path.as_deref()),
+                    e
+                )
+            })?;
+            Some(Mutex::new(client))
+        } else {
+            None
+        };
+
+        let jsonl_path = if config.has_output(OUTPUT_FILE_JSONL) {
+            let path = config.jsonl_path();
+            if path.is_none() {
+                eprintln!("Could not determine a path for the file-jsonl output, disabling it");
+            }
+            path
+        } else {
+            None
+        };
+
+        let mqtt = if config.has_output(OUTPUT_MQTT) {
+            // Validated at startup
+            let (host, port) = config
+                .mqtt_address()
+                .ok_or("mqtt output needs a valid mqtt_broker")?;
+            Some((connect_mqtt(host, port), config.mqtt_topic.clone()))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            client,
+            jsonl_path,
+            mqtt,
+            created_buckets: Mutex::new(HashSet::new()),
+            heartbeat_buckets: Mutex::new(HashSet::new()),
+            retry_schedule: RetrySchedule::from_config(config),
+            server_reachable: AtomicBool::new(true),
+            server_retry: Mutex::new((Instant::now(), SERVER_BACKOFF_MIN)),
+        })
+    }
+
+    /// Whether to send to aw-server now: always while it is reachable, otherwise
+    /// once the backoff has passed
+    fn server_attempt_due(&self) -> bool {
+        self.server_reachable.load(Ordering::Relaxed)
+            || Instant::now() >= self.server_retry.lock().unwrap().0
+    }
+
+    /// Record whether an aw-server request got an answer, even an error response.
+    /// Each unanswered request while it is down doubles the backoff
+    fn record_server_answer(&self, answered: bool) {
+        if answered {
+            if !self.server_reachable.swap(true, Ordering::Relaxed) {
+                println!("aw-server is reachable again");
+            }
+            return;
+        }
+
+        let mut retry = self.server_retry.lock().unwrap();
+        let backoff = if self.server_reachable.swap(false, Ordering::Relaxed) {
+            eprintln!(
+                "aw-server can't be reached, dropping its events and trying again in {:?}",
+                SERVER_BACKOFF_MIN
+            );
+            SERVER_BACKOFF_MIN
+        } else {
+            (retry.1 * 2).min(SERVER_BACKOFF_MAX)
+        };
+        *retry = (Instant::now() + backoff, backoff);
+    }
+
+    /// JSON object holding the bucket ID, the pulsetime for heartbeats and the event,
+    /// as written by the file-jsonl and mqtt outputs
+    fn record(bucket_id: &str, event: &Event, pulsetime: Option<f64>) -> Result<String, String> {
+        let mut record = Map::new();
+        record.insert(
+            "bucket_id".to_string(),
+            Value::String(bucket_id.to_string()),
+        );
+        if let Some(pulsetime) = pulsetime {
+            record.insert("pulsetime".to_string(), Value::from(pulsetime));
+        }
+        record.insert(
+            "event".to_string(),
+            serde_json::to_value(event).map_err(|e| e.to_string())?,
+        );
+        serde_json::to_string(&record).map_err(|e| e.to_string())
+    }
+
+    /// Append one line holding the bucket ID, the pulsetime for heartbeats and the event
+    fn append_jsonl(
+        &self,
+        bucket_id: &str,
+        event: &Event,
+        pulsetime: Option<f64>,
+    ) -> Option<Result<(), String>> {
+        let path = self.jsonl_path.as_ref()?;
+
+        let result = (|| {
+            let line = Self::record(bucket_id, event, pulsetime)?;
+
+            if let Some(parent) = path.parent() {
+                create_dir_all(parent).map_err(|e| e.to_string())?;
+            }
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| e.to_string())?;
+            writeln!(file, "{}", line).map_err(|e| e.to_string())
+        })();
+
+        Some(result.map_err(|e| format!("file-jsonl: {}", e)))
+    }
+
+    /// Queue the event for publishing to the MQTT broker. This never blocks: when
+    /// the broker is unreachable and the queue is full, the event is dropped
+    fn publish_mqtt(
+        &self,
+        bucket_id: &str,
+        event: &Event,
+        pulsetime: Option<f64>,
+    ) -> Option<Result<(), String>> {
+        let (client, topic) = self.mqtt.as_ref()?;
+
+        let result = Self::record(bucket_id, event, pulsetime).and_then(|payload| {
+            client
+                .try_publish(topic.as_str(), QoS::AtLeastOnce, false, payload)
+                .map_err(|e| e.to_string())
+        });
+        Some(result.map_err(|e| format!("mqtt: {}", e)))
+    }
+
+    /// Merge the results of each output into a single error listing every failure
+    fn combine(results: [Option<Result<(), String>>; 3]) -> Result<(), String> {
+        let errors: Vec<String> = results
+            .into_iter()
+            .flatten()
+            .filter_map(Result::err)
+            .collect();
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("; "))
+        }
+    }
+}
+
+impl EventSink for EventSinks {
+    fn ensure_bucket(&self, bucket_id: &str, event_type: &str) -> Result<(), String> {
+        let Some(client) = self.client.as_ref().filter(|_| self.server_attempt_due()) else {
+            return Ok(());
+        };
+
+        let mut created_buckets = self.created_buckets.lock().unwrap();
+        if created_buckets.contains(bucket_id) {
+            return Ok(());
+        }
+
+        // Retried like the buckets created at startup, but at most once per delay
+        // and only while the server is reachable, so it can't hold up the loop
+        self.retry_schedule
+            .bounded()
+            .run_while(
+                &format!("Creating bucket {}", bucket_id),
+                || self.server_attempt_due(),
+                || {
+                    let result = try_create_bucket(&client.lock().unwrap(), bucket_id, event_type);
+                    self.record_server_answer(
+                        !result
+                            .as_ref()
+                            .is_err_and(|e| e.is_connect() || e.is_timeout()),
+                    );
+                    result.map_err(|e| e.to_string())
+                },
+            )
+            .map_err(|e| format!("aw: failed to create bucket {}: {}", bucket_id, e))?;
+        created_buckets.insert(bucket_id.to_string());
+        Ok(())
+    }
+
+    /// Send a heartbeat to aw-server, append the event to the JSONL file and publish it over MQTT.
+    /// A bucket that was just created may not accept events yet, so the first
+    /// heartbeat to each bucket is retried following retry_schedule_ms, at most
+    /// once per delay so a server that is down can't hold up the loop. Retrying
+    /// stops as soon as the server is marked unreachable. Later failures are left
+    /// to the caller's usual error handling
+    fn send(&self, bucket_id: &str, event: &Event, pulsetime: f64) -> Result<(), String> {
+        let aw_result = self
+            .client
+            .as_ref()
+            .filter(|_| self.server_attempt_due())
+            .map(|client| {
+                let first = self
+                    .heartbeat_buckets
+                    .lock()
+                    .unwrap()
+                    .insert(bucket_id.to_string());
+                let send = || {
+                    let result = client
+                        .lock()
+                        .unwrap()
+                        .heartbeat(bucket_id, event, pulsetime);
+                    self.record_server_answer(
+                        !result
+                            .as_ref()
+                            .is_err_and(|e| e.is_connect() || e.is_timeout()),
+                    );
+                    result.map_err(|e| format!("aw: {}", e))
+                };
+
+                if first {
+                    self.retry_schedule.bounded().run_while(
+                        &format!(
+                            "First heartbeat to {} (the new bucket may not be ready yet)",
+                            bucket_id
+                        ),
+                        || self.server_attempt_due(),
+                        send,
+                    )
+                } else {
+                    send()
+                }
+            });
+        let file_result = self.append_jsonl(bucket_id, event, Some(pulsetime));
+        let mqtt_result = self.publish_mqtt(bucket_id, event, Some(pulsetime));
+
+        Self::combine([aw_result, file_result, mqtt_result])
+    }
+
+    /// Insert an event into aw-server, append it to the JSONL file and publish it over MQTT
+    fn insert_event(&self, bucket_id: &str, event: &Event) -> Result<(), String> {
+        let aw_result = self
+            .client
+            .as_ref()
+            .filter(|_| self.server_attempt_due())
+            .map(|client| {
+                let result = client.lock().unwrap().insert_event(bucket_id, event);
+                self.record_server_answer(
+                    !result
+                        .as_ref()
+                        .is_err_and(|e| e.is_connect() || e.is_timeout()),
+                );
+                result.map_err(|e| format!("aw: {}", e))
+            });
+        let file_result = self.append_jsonl(bucket_id, event, None);
+        let mqtt_result = self.publish_mqtt(bucket_id, event, None);
+
+        Self::combine([aw_result, file_result, mqtt_result])
+    }
+}
+
+/// Connect to an MQTT broker. The connection is driven on its own thread, which
+/// reconnects after failures, so publishing never waits for the broker
+fn connect_mqtt(host: String, port: u16) -> MqttClient {
+    println!("Publishing events to MQTT broker {}:{}", host, port);
+    let mut options = MqttOptions::new(
+        format!("aw-watcher-network-{}", std::process::id()),
+        host,
+        port,
+    );
+    options.set_keep_alive(Duration::from_secs(30));
+    // Events queued while the broker can't be reached; later ones are dropped
+    let (client, mut connection) = MqttClient::new(options, 100);
+
+    thread::spawn(move || {
+        let mut connected = true;
+        for notification in connection.iter() {
+            match notification {
+                Ok(_) => connected = true,
+                Err(e) => {
+                    // Log once per outage, not on every reconnect attempt
+                    if connected {
+                        eprintln!("MQTT connection failed, retrying: {}", e);
+                        connected = false;
+                    }
+                    sleep(MQTT_RECONNECT_DELAY);
+                }
+            }
+        }
+    });
+    client
+}
+
+/// Sends metrics to a StatsD server over UDP. Send failures are ignored so that
+/// metrics never get in the way of event reporting
+struct Statsd {
+    socket: UdpSocket,
+    addr: SocketAddr,
+}
+
+impl Statsd {
+    /// Prefix of every metric name
+    const PREFIX: &str = "aw_watcher_network";
+
+    fn new(addr: &str) -> Result<Self, String> {
+        let addr = addr
+            .to_socket_addrs()
+            .map_err(|e| format!("Invalid StatsD address {}: {}", addr, e))?
+            .next()
+            .ok_or_else(|| format!("StatsD address {} did not resolve", addr))?;
+
+        let bind_addr = if addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(bind_addr)
+            .map_err(|e| format!("Failed to open StatsD socket: {}", e))?;
+
+        Ok(Self { socket, addr })
+    }
+
+    /// Set up the client for `statsd_addr`, if configured
+    fn from_config(config: &AppConfig) -> Option<Self> {
+        let addr = config.statsd_addr.as_deref()?;
+        match Self::new(addr) {
+            Ok(statsd) => Some(statsd),
+            Err(e) => {
+                eprintln!("{}, not sending metrics", e);
+                None
+            }
+        }
+    }
+
+    fn gauge(&self, name: &str, value: f64) {
+        self.send(&format!("{}.{}:{}|g", Self::PREFIX, name, value));
+    }
+
+    fn count(&self, name: &str, value: u64) {
+        self.send(&format!("{}.{}:{}|c", Self::PREFIX, name, value));
+    }
+
+    fn send(&self, metric: &str) {
+        let _ = self.socket.send_to(metric.as_bytes(), self.addr);
+    }
+}
+
+/// Reports watcher errors as events to the diagnostics bucket
+struct Diagnostics {
+    sinks: Arc<dyn EventSink>,
+    bucket_id: String,
+    /// When an event was last sent for each (component, level) pair, used for rate-limiting
+    last_sent: Mutex<HashMap<(String, String), Instant>>,
+}
+
+impl Diagnostics {
+    fn new(sinks: Arc<dyn EventSink>, bucket_id: String) -> Self {
+        Self {
+            sinks,
+            bucket_id,
+            last_sent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Send a diagnostics event, unless one was sent for the same component and level recently
+    fn report(&self, level: &str, component: &str, message: &str) {
+        {
+            let mut last_sent = self.last_sent.lock().unwrap();
+            let key = (component.to_string(), level.to_string());
+            if last_sent
+                .get(&key)
+                .is_some_and(|sent| sent.elapsed() < DIAGNOSTICS_MIN_INTERVAL)
+            {
+                return;
+            }
+            last_sent.insert(key, Instant::now());
+        }
+
+        let mut data_map = Map::new();
+        data_map.insert("level".to_string(), Value::String(level.to_string()));
+        data_map.insert(
+            "component".to_string(),
+            Value::String(component.to_string()),
+        );
+        data_map.insert("message".to_string(), Value::String(message.to_string()));
+        add_session_id(&mut data_map);
+
+        let event = Event {
+            id: None,
+            timestamp: Utc::now(),
+            duration: TimeDelta::zero(),
+            data: data_map,
+        };
+
+        // Don't report failures to send diagnostics as diagnostics, to avoid loops
+        if let Err(e) = self.sinks.insert_event(&self.bucket_id, &event) {
+            eprintln!("Error sending diagnostics event: {}", e);
+        }
+    }
+}
+
+/// Show a desktop notification; does nothing in builds without the `notify` feature
+#[cfg(feature = "notify")]
+fn notify(summary: &str, body: &str) {
+    if let Err(e) = notify_rust::Notification::new()
+        .appname("aw-watcher-network")
+        .summary(summary)
+        .body(body)
+        .show()
+    {
+        eprintln!("Error showing notification: {}", e);
+    }
+}
+
+#[cfg(not(feature = "notify"))]
+fn notify(_summary: &str, _body: &str) {}
+
+/// Start a configured on_online/on_offline command without waiting for it. A
+/// thread reaps the child so finished commands don't linger as zombies
+fn run_transition_command(command: &str, outage_seconds: u64, ssid: &str) {
+    let mut parts = command.split_whitespace();
+    let Some(program) = parts.next() else {
+        return;
+    };
+
+    match Command::new(program)
+        .args(parts)
+        .env("AW_NETWORK_OUTAGE_SECONDS", outage_seconds.to_string())
+        .env("AW_NETWORK_SSID", ssid)
+        .spawn()
+    {
+        Ok(mut child) => {
+            println!("Started '{}' (pid {})", command, child.id());
+            thread::spawn(move || child.wait());
+        }
+        Err(e) => eprintln!("Failed to start '{}': {}", command, e),
+    }
+}
+
+/// Copy the event timestamp into its data as an RFC 3339 string with a "Z" suffix
+fn embed_timestamp(event: &mut Event) {
+    event.data.insert(
+        "timestamp".to_string(),
+        Value::String(event.timestamp.to_rfc3339_opts(SecondsFormat::Millis, true)),
+    );
+}
+
+/// Add the include_host_info fields, if enabled, to event data
+fn add_host_info(data: &mut Map<String, Value>) {
+    if let Some(host_info) = HOST_INFO.get() {
+        data.extend(host_info.clone());
+    }
+    add_session_id(data);
+}
+
+/// Add the include_session_id field, if enabled, to event data
+fn add_session_id(data: &mut Map<String, Value>) {
+    if let Some(session_id) = SESSION_ID.get() {
+        data.insert("session_id".to_string(), Value::String(session_id.clone()));
+    }
+}
+
+/// Duration of a heartbeat event sent at `now`: the interval, or with accurate_durations
+/// the time since the previous heartbeat, capped at MAX_DURATION_INTERVALS intervals
+fn event_duration(
+    config: &AppConfig,
+    interval: u64,
+    last_heartbeat: Option<Instant>,
+    now: Instant,
+) -> TimeDelta {
+    match last_heartbeat {
+        Some(sent) if config.accurate_durations => {
+            let duration = now
+                .duration_since(sent)
+                .min(interval_duration(interval) * MAX_DURATION_INTERVALS);
+            TimeDelta::milliseconds(duration.as_millis() as i64)
+        }
+        _ => TimeDelta::seconds(interval as i64),
+    }
+}
+
+/// Duration of an interval configured in seconds, shortened to 50ms in test mode
+fn interval_duration(seconds: u64) -> Duration {
+    if TEST_FAST.load(Ordering::Relaxed) {
+        TEST_FAST_INTERVAL
+    } else {
+        Duration::from_secs(seconds)
+    }
+}
+
+/// Time left until the next multiple of `interval` on the system clock
+fn until_clock_boundary(interval: Duration) -> Duration {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let interval = interval.as_nanos().max(1);
+    Duration::from_nanos((interval - since_epoch % interval) as u64)
+}
+
+/// Whether the `--duration` deadline has passed
+fn shutdown_due() -> bool {
+    DEADLINE
+        .get()
+        .is_some_and(|deadline| Instant::now() >= *deadline)
+}
+
+/// Sleep for `duration`, waking early if the `--duration` deadline comes first
+fn sleep_until_deadline(duration: Duration) {
+    let duration = match DEADLINE.get() {
+        Some(deadline) => duration.min(deadline.saturating_duration_since(Instant::now())),
+        None => duration,
+    };
+    sleep(duration);
+}
+
+/// Round-trip a sentinel event through a temporary bucket on aw-server
+fn selftest(base_path: Option<&str>) -> Result<(), String> {
+    let hostname = get_hostname()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| "unknown-host".to_string());
+    let bucket_id = format!("aw-watcher-network-selftest_{}", hostname);
+
+    let client = ServerClient::new(base_path).map_err(|e| {
+        format!(
+            "failed to create aw-server client for {}: {}",
+            server_address(base_path),
+            e
+        )
+    })?;
+
+    println!("Creating bucket {}", bucket_id);
+    create_bucket(&client, &bucket_id, "selftest")
+        .map_err(|e| format!("failed to create bucket: {}", e))?;
+
+    // Run the checks, then delete the bucket whatever the outcome
+    let result = (|| {
+        let sentinel = format!("selftest-{}", Utc::now().timestamp_millis());
+        let mut data_map = Map::new();
+        data_map.insert("sentinel".to_string(), Value::String(sentinel.clone()));
+        let event = Event {
+            id: None,
+            timestamp: Utc::now(),
+            duration: TimeDelta::zero(),
+            data: data_map,
+        };
+
+        println!("Sending sentinel event");
+        client
+            .insert_event(&bucket_id, &event)
+            .map_err(|e| format!("failed to send event: {}", e))?;
+
+        println!("Reading the event back");
+        let events = client
+            .get_events(&bucket_id, Some(10))
+            .map_err(|e| format!("failed to read events: {}", e))?;
+
+        if events
+            .iter()
+            .any(|event| event.data.get("sentinel") == Some(&Value::String(sentinel.clone())))
+        {
+            Ok(())
+        } else {
+            Err("the sentinel event was not found in the bucket".to_string())
+        }
+    })();
+
+    println!("Deleting bucket {}", bucket_id);
+    let cleanup = client
+        .delete_bucket(&bucket_id)
+        .map_err(|e| format!("failed to delete bucket {}: {}", bucket_id, e));
+
+    result.and(cleanup)
+}
+
+/// Print the version and hostname aw-server reports at /api/0/info
+fn check_server(base_path: Option<&str>) -> Result<(), String> {
+    let client = ServerClient::new(base_path).map_err(|e| {
+        format!(
+            "failed to create aw-server client for {}: {}",
+            server_address(base_path),
+            e
+        )
+    })?;
+
+    let info = client.get_info().map_err(|e| {
+        format!(
+            "failed to reach aw-server at {}: {}",
+            server_address(base_path),
+            e
+        )
+    })?;
+
+    println!("aw-server at {}", server_address(base_path));
+    println!("Version: {}", info.version);
+    println!("Hostname: {}", info.hostname);
+    if info.testing {
+        println!("Running in testing mode");
+    }
+    Ok(())
+}
+
+/// Copy every event of the `old_id` bucket into whichever of `current_buckets`
+/// (bucket ID and event type) has the same event type, creating it if needed,
+/// and optionally delete the old bucket
+fn merge_bucket(
+    base_path: Option<&str>,
+    old_id: &str,
+    current_buckets: &[(&str, &str)],
+    delete_old: bool,
+) -> Result<(), String> {
+    let client = ServerClient::new(base_path).map_err(|e| {
+        format!(
+            "failed to create aw-server client for {}: {}",
+            server_address(base_path),
+            e
+        )
+    })?;
+
+    let old_bucket = client
+        .get_bucket(old_id)
+        .map_err(|e| format!("failed to get bucket {}: {}", old_id, e))?;
+    let Some(&(bucket_id, event_type)) = current_buckets
+        .iter()
+        .find(|(_, event_type)| *event_type == old_bucket._type)
+    else {
+        let types: Vec<&str> = current_buckets.iter().map(|(_, t)| *t).collect();
+        return Err(format!(
+            "bucket {} has type '{}', expected one of: {}",
+            old_id,
+            old_bucket._type,
+            types.join(", ")
+        ));
+    };
+    if bucket_id == old_id {
+        return Err(format!("{} is already the current bucket", old_id));
+    }
+
+    let mut events = client
+        .get_events(old_id, None)
+        .map_err(|e| format!("failed to read events from {}: {}", old_id, e))?;
+    // The IDs belong to the old bucket
+    for event in &mut events {
+        event.id = None;
+    }
+    let count = events.len();
+
+    create_bucket(&client, bucket_id, event_type)
+        .map_err(|e| format!("failed to create bucket {}: {}", bucket_id, e))?;
+    client
+        .insert_events(bucket_id, events)
+        .map_err(|e| format!("failed to insert events into {}: {}", bucket_id, e))?;
+    println!("Migrated {} events from {} to {}", count, old_id, bucket_id);
+
+    if delete_old {
+        client
+            .delete_bucket(old_id)
+            .map_err(|e| format!("failed to delete bucket {}: {}", old_id, e))?;
+        println!("Deleted bucket {}", old_id);
+    }
+    Ok(())
+}
+
+/// Print the raw output of every command the Wi-Fi scan runs, then what the
+/// scan parses. Returns whether the scan succeeded
+fn debug_wifi(config: &AppConfig) -> bool {
+    for command in wifi_debug_commands(config) {
+        println!("$ {}", command);
+        match run_scan_command(&command) {
+            Ok(output) => {
+                println!("exit status: {}", output.status);
+                println!("--- stdout ---");
+                print!("{}", String::from_utf8_lossy(&output.stdout));
+                println!("--- stderr ---");
+                print!("{}", String::from_utf8_lossy(&output.stderr));
+            }
+            Err(e) => println!("{}", e),
+        }
+        println!();
+    }
+
+    match get_wifi_ssids(config) {
+        Ok(scan) => {
+            println!("Parsed result:");
+            println!("connected_ssid: {:?}", scan.connected_ssid);
+            println!("ssids: {:?}", scan.ssids);
+            true
+        }
+        Err(e) => {
+            println!("Scan failed: {}", e);
+            false
+        }
+    }
+}
+
+/// Commands the Wi-Fi scan runs on this platform with the given config
+fn wifi_debug_commands(config: &AppConfig) -> Vec<String> {
+    #[cfg(target_os = "macos")]
+    {
+        vec![
+            config
+                .scan_command()
+                .unwrap_or("system_profiler SPAirPortDataType")
+                .to_string(),
+        ]
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        let mut commands = vec!["nmcli -t connection show --active".to_string()];
+        match config.scan_command() {
+            Some(command) => commands.push(command.to_string()),
+            None => {
+                for backend in &config.linux_wifi_backends {
+                    match backend.as_str() {
+                        "nmcli" => commands.push(
+                            "nmcli -t -f SSID,SIGNAL,CHAN,SECURITY device wifi list".to_string(),
+                        ),
+                        "iwlist" => commands.push("iwlist scanning".to_string()),
+                        "iw" => {
+                            if let Some(interface) = wireless_interface_linux() {
+                                commands.push(format!("iw dev {} link", interface));
+                                commands.push(format!("iw dev {} scan", interface));
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+        commands
+    }
+
+    #[cfg(target_os = "windows")]
+    {
+        vec![
+            "netsh wlan show interfaces".to_string(),
+            config
+                .scan_command()
+                .unwrap_or("netsh wlan show networks")
+                .to_string(),
+        ]
+    }
+}
+
+/// Directory holding the watcher's config.toml and config.d/
+fn watcher_config_dir() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join("activitywatch").join("aw-watcher-network"))
+}
+
+/// Render a fully commented config file documenting every option with its default value
+fn annotated_default_config() -> String {
+    let defaults = AppConfig::default();
+
+    format!(
+        r##"# Configuration for aw-watcher-network-rs
+#
+# Every option is listed with its default value. Options that are unset by
+# default are commented out; remove the leading "#" to set them.
+
+# How often to check network status, in seconds
+polling_interval = {polling_interval}
+
+# How often to scan for Wi-Fi networks, in seconds
+# Higher values reduce system resource usage, lower values provide more frequent updates
+wifi_scan_interval = {wifi_scan_interval}
+
+# Scan less often while on AC power, typically a docked laptop that isn't moving,
+# and at wifi_scan_interval on battery. The power source is checked on every
+# scan; machines without a battery always count as on AC power
+# wifi_scan_interval_docked = 1800
+
+# Probe connectivity more often than heartbeats are sent, in seconds.
+# A heartbeat is still sent every polling_interval, or immediately when the state changes
+# probe_interval = 1
+
+# Poll on multiples of the interval on the system clock (e.g. every :00 and :10
+# of a minute for a 10s interval) instead of relative to when the watcher
+# started, so timing doesn't drift. Applies to network probes and Wi-Fi scans.
+# A poll that runs past the next boundary skips it and waits for the one after
+align_to_clock = {align_to_clock}
+
+# Polling interval used after 3 consecutive offline checks, in seconds.
+# Reverts to polling_interval as soon as the network is back online
+# offline_polling_interval = 60
+
+# Wait this many milliseconds at startup before the first connectivity check and
+# Wi-Fi scan, e.g. to let the network come up when the watcher starts at boot
+startup_delay_ms = {startup_delay_ms}
+
+# Send a network event with this title ("unknown" or "offline") right at startup,
+# so the timeline covers the time until the first connectivity check completes,
+# including startup_delay_ms. The real state follows as soon as it is known
+# initial_state = "unknown"
+
+# Port used when probing the built-in DNS servers (1.1.1.1, 8.8.8.8, 9.9.9.9)
+default_probe_port = {default_probe_port}
+
+# Milliseconds to wait for each probe connection
+connect_timeout_ms = {connect_timeout_ms}
+
+# How connectivity is checked. "tcp" probes the targets below. "ping" sends them
+# an ICMP echo request instead, ignoring the port, for networks that block TCP
+# but allow ping; without permission to open ICMP sockets (root on Linux unless
+# your group is in net.ipv4.ping_group_range, admin on Windows) it falls back to
+# "tcp" with a warning. "script" runs
+# connectivity_script each poll instead: exit code 0 means online, anything else
+# offline, and whatever it prints is added to network events as "script_output".
+# The script is killed and counts as offline after connect_timeout_ms. It runs
+# directly (not through a shell) with the watcher's permissions, so only point
+# this at a script that nobody else can modify
+connectivity_mode = "{connectivity_mode}"
+# connectivity_script = "/home/me/bin/check-vpn.sh"
+
+# Custom host:port targets to probe instead of the built-in DNS servers.
+# default_probe_port does not apply to these. IPv6 addresses go in brackets.
+# Append "@<n>ms" or "@<n>s" to give a target its own timeout instead of
+# connect_timeout_ms, e.g. "192.168.1.1:80@50ms"
+connectivity_targets = []
+# connectivity_targets = ["1.1.1.1:443", "example.com:80@2s", "[2606:4700:4700::1111]:53"]
+
+# Each poll, probe only this many of the targets, picked at random, instead of
+# trying all of them in order. Spreads the load across providers, e.g. for many
+# machines probing the same public DNS servers; over time every target is used
+# targets_per_poll = 1
+
+# A host:port you care about in particular, e.g. your DNS-over-HTTPS provider.
+# It is probed on its own and its result is added to network events as
+# "primary_reachable", while "online" still only needs any target to respond
+# primary_target = "1.1.1.1:443"
+
+# Reuse the resolved addresses of hostname targets for this many seconds instead
+# of resolving them on every probe. 0 disables the cache
+dns_cache_ttl_seconds = {dns_cache_ttl_seconds}
+
+# A host:port on the local network (e.g. your router's web interface) probed
+# along with the connectivity targets. Its result is added to network events as
+# "lan_reachable", telling a local outage from an upstream one
+# lan_probe_target = "192.168.1.1:80"
+
+# Also probe IPv6 connectivity and add an "ipv6" field to network events
+check_ipv6 = {check_ipv6}
+
+# Add the connected Wi-Fi SSID as an "ssid" field to network events
+include_ssid_in_network_event = {include_ssid_in_network_event}
+
+# Add an "is_default_route" field to network events telling whether the
+# successful probe used the interface of the system's default route
+include_default_route = {include_default_route}
+
+# When greater than 0, add the probe latency ("latency_ms") and its average over
+# the last N successful probes ("avg_latency_ms") to network events
+latency_window = {latency_window}
+
+# Report latency_ms and avg_latency_ms with this many decimals instead of as
+# whole milliseconds, e.g. 0.42 for a LAN target reached in under a millisecond
+# latency_precision = 2
+
+# Also add the latest latency as a coarse range ("latency_bucket", e.g. "10-50ms")
+# for cleaner charting. Each number is the upper bound in ms of a range; latencies
+# of at least the last one are reported as ">=250ms". latency_ms is kept
+# latency_buckets = [10, 50, 100, 250]
+
+# Add the negotiated link speed of the active connection ("link_speed_mbps") to
+# Wi-Fi events, and to network events for wired connections on Linux
+include_link_speed = {include_link_speed}
+
+# Add the Wi-Fi standard of the connected network ("phy_mode", e.g. "802.11ax")
+# to Wi-Fi events. Linux needs iw for this. Omitted when it can't be detected
+include_phy_mode = {include_phy_mode}
+
+# Add the tool the Wi-Fi scan was done with ("scan_tool") to Wi-Fi events:
+# "nmcli", "iwlist" or "iw" on Linux, "system_profiler" on macOS, "netsh" on
+# Windows, or the program of a custom scan command
+include_scan_tool = {include_scan_tool}
+
+# When no Wi-Fi networks are visible, e.g. with Wi-Fi off on a wired machine,
+# send the "No Wi-Fi networks" event only once instead of on every scan. The
+# timeline then only shows the first scan of such a stretch
+suppress_repeated_empty_wifi = {suppress_repeated_empty_wifi}
+
+# Networks that don't broadcast their name show up in scans with an empty SSID
+# and are left out. Set this to list them as a single "<hidden>" entry among the
+# scanned SSIDs instead. Detected on Linux and Windows
+report_hidden_ssids = {report_hidden_ssids}
+
+# "aggregate" sends one Wi-Fi event per scan, titled after the connected network
+# and listing every visible SSID. "per_network" instead sends a heartbeat per
+# visible network on every scan, with "ssid", "connected" (true for the network
+# you are on) and, where the platform reports them, "signal" (dBm on macOS,
+# percent elsewhere), "channel" and "security". Expect one event per network per
+# scan, since heartbeats of different networks never merge
+wifi_event_granularity = "{wifi_event_granularity}"
+
+# Add the addresses of the interface the probe went over as "ipv4_addr" and
+# "ipv6_addr" fields to network events. Off by default for privacy
+include_ip_addresses = {include_ip_addresses}
+
+# Add the IPv4 subnet of the interface the probe went over as "subnet", e.g.
+# "192.168.1.0/24", to tell apart networks that share an SSID
+include_subnet = {include_subnet}
+
+# Add "host" (the hostname), "os" (e.g. "linux") and, when it can be detected,
+# "os_version" to network and Wi-Fi events, for dashboards that only look at
+# event data. The bucket IDs already include the hostname
+include_host_info = {include_host_info}
+
+# Add a "session_id" to every event: a random UUID generated when the watcher
+# starts and shared by all its events, to tell runs apart and spot restarts
+include_session_id = {include_session_id}
+
+# Add an "airplane_mode" field to network events. Linux uses NetworkManager's
+# radio states, Windows the system radio state and macOS treats Wi-Fi and
+# Bluetooth both being off as airplane mode. Omitted when it can't be detected
+include_airplane_mode = {include_airplane_mode}
+
+# Add a "dns_servers" list to network events with the DNS servers the system is
+# configured to use: from resolvectl or /etc/resolv.conf on Linux, scutil on
+# macOS and Get-DnsClientServerAddress on Windows
+include_dns_servers = {include_dns_servers}
+
+# Add a "connection_type" field to network events classifying the interface the
+# probe went over as "cellular", "wifi", "vpn" or "wired"
+include_connection_type = {include_connection_type}
+
+# Add an "is_wifi" field to network events telling whether the probe went over a
+# Wi-Fi interface
+include_is_wifi = {include_is_wifi}
+
+# Add a "gateway" field to network events with the IPv4 gateway of the default
+# route. Omitted when there is none or it can't be determined
+include_gateway = {include_gateway}
+
+# Repeat the event timestamp inside the event data as a "timestamp" string
+# (RFC 3339 in UTC, e.g. "2024-05-01T12:00:00.000Z"), for consumers that only
+# read the data of network and Wi-Fi events. Since the data then differs on
+# every heartbeat, aw-server stores each heartbeat as a separate event
+embed_timestamp = {embed_timestamp}
+
+# Set the duration of network and Wi-Fi events to the time since the previous
+# heartbeat instead of the polling or scan interval, so polls that ran late
+# show up as they happened. The first event uses the interval, and durations
+# are capped at 4 intervals, e.g. after the computer slept
+accurate_durations = {accurate_durations}
+
+# Also probe one connectivity target on port 53 and one on port 443: configured
+# targets on those ports, or else the first target's host on each port. When only
+# one of the two works, the title is "degraded" and a "degraded_reason" field
+# tells which probe failed
+report_degraded = {report_degraded}
+
+# Keep track of how often each target responded over its last 20 probes and
+# try the most reliable targets first, so fewer probes wait for a timeout. With
+# statsd_addr set, each target's reliability is sent as a percentage gauge
+# .target_reliability.<target>. Doesn't apply with include_quality
+prioritize_reliable_targets = {prioritize_reliable_targets}
+
+# Probe every target on each poll instead of stopping at the first one that
+# responds, and add the fraction that was reachable (0.0-1.0) to network events
+# as "quality". Unreachable targets each add their timeout to the poll
+include_quality = {include_quality}
+
+# Fraction of targets that must be reachable for the network to count as
+# online. Setting it probes every target like include_quality. By default any
+# single reachable target is enough
+# quality_online_threshold = 0.5
+
+# Add an "online_ratio" field to network events: the fraction of time (0.0-1.0)
+# the network was online since the watcher started. Resets on restart
+include_uptime_stats = {include_uptime_stats}
+
+# Add a "previous_state_duration_seconds" field to the network event sent when
+# the network goes online or offline: how long the state before it lasted. The
+# first state counts from when the watcher started
+include_previous_state_duration = {include_previous_state_duration}
+
+# Add a "consecutive_same_state" field to network events: how many polls in a row
+# the network has been online (or offline), starting at 1 on each change. Low
+# numbers point to a flapping connection. Since it changes on every poll, each
+# heartbeat is stored as its own event
+include_stability_counter = {include_stability_counter}
+
+# Add an "overrun_total" field to network and Wi-Fi events: how many polls or
+# scans since startup took longer than their interval. With statsd_addr set the
+# overruns are also counted as .overrun_total.network and .overrun_total.wifi
+include_timing_stats = {include_timing_stats}
+
+# Only poll between these local times ("HH:MM-HH:MM"). Outside the window no
+# probes, scans or heartbeats happen, leaving a gap in the timeline. Windows
+# may wrap past midnight, e.g. "22:00-06:00"
+# active_hours = "08:00-22:00"
+
+# Check connectivity and report it to the network bucket
+enable_network_watcher = {enable_network_watcher}
+
+# Scan for Wi-Fi networks and report them to the Wi-Fi bucket.
+# At least one of the two watchers must be enabled
+enable_wifi_watcher = {enable_wifi_watcher}
+
+# Custom bucket IDs. Placeholders are replaced at startup: {{hostname}}, {{user}},
+# {{os}} (linux, macos or windows) and {{iface}} (interface of the default route,
+# or "unknown"). Any other placeholder is an error
+# network_bucket_id = "aw-watcher-network_{{hostname}}"
+# wifi_bucket_id = "aw-watcher-wifi_{{hostname}}"
+
+# On Saturdays and Sundays (local time), send network and Wi-Fi events to
+# buckets with this suffix appended to their IDs, e.g. "aw-watcher-network_<host>-weekend".
+# The buckets are created when first needed, if create_buckets is enabled
+# weekend_bucket_suffix = "-weekend"
+
+# How network events are sent. "heartbeat" sends one every polling interval.
+# "transitions" only sends an event when the state changes, covering the whole
+# period the previous state lasted. aw-server can't hold an event open, so the
+# current state only shows up once it ends, and it is lost if the watcher stops
+event_mode = "{event_mode}"
+
+# In "heartbeat" mode, skip network heartbeats whose data is the same as the
+# last one sent, and only resend it every max_heartbeat_gap_seconds to keep the
+# event going. Fields that change on every poll, such as latency_ms, defeat this
+dedup_network_events = {dedup_network_events}
+max_heartbeat_gap_seconds = {max_heartbeat_gap_seconds}
+
+# Send a heartbeat at least every this many seconds even while nothing changes,
+# so the timeline never lags behind by more than that if the watcher dies. This
+# applies where heartbeats are otherwise skipped: in "transitions" mode (the
+# current state's event is sent with its length so far), with
+# dedup_network_events and with suppress_repeated_empty_wifi. 0 disables it
+keepalive_interval_seconds = {keepalive_interval_seconds}
+
+# Where events are sent. Every event goes to each listed output, and a failing
+# output doesn't stop the others: "aw" sends to aw-server, "file-jsonl" appends
+# to a JSON Lines file, "mqtt" publishes to mqtt_broker
+outputs = ["aw"]
+
+# File used by the file-jsonl output. Defaults to events.jsonl in the
+# activitywatch/aw-watcher-network folder of the user data directory
+# jsonl_path = "/path/to/events.jsonl"
+
+# Broker and topic used by the mqtt output, e.g. for Home Assistant. Each event
+# is published as JSON with "bucket_id", "pulsetime" (for heartbeats) and "event"
+# fields, like the lines of the file-jsonl output. The port defaults to 1883
+# mqtt_broker = "homeassistant.local:1883"
+mqtt_topic = "{mqtt_topic}"
+
+# Path prefix aw-server is served under when it is behind a reverse proxy, so
+# requests go to e.g. http://localhost:5600/activitywatch/api/0/...
+# server_base_path = "/activitywatch"
+
+# Skip the Wi-Fi watcher (no bucket, no scanning) when no wireless interface is
+# found at startup, e.g. on desktops and servers without Wi-Fi, or when none of
+# the platform's Wi-Fi scanning tools is installed
+wifi_autodisable = {wifi_autodisable}
+
+# Show a desktop notification when the network goes offline or comes back,
+# including how long the outage lasted. Only works in builds with the "notify"
+# cargo feature
+notify_on_state_change = {notify_on_state_change}
+
+# Commands (with arguments, no shell) run in the background when the network
+# comes back online or goes offline, e.g. to reconnect a VPN. They get
+# AW_NETWORK_OUTAGE_SECONDS (0 when going offline) and AW_NETWORK_SSID (empty
+# when not on Wi-Fi) in their environment. They run as the watcher's user on
+# every transition, so only point these at scripts nobody else can modify
+# on_online_command = "/home/me/bin/vpn-reconnect"
+# on_offline_command = "/home/me/bin/unmount-shares"
+
+# Seconds after startup during which going offline or back online doesn't
+# trigger notifications or on_online/on_offline commands, so a network that is
+# still coming up isn't reported as an outage. Nor are Wi-Fi or proxy changes
+# sent, previous_state_duration_seconds set, or transitions-mode periods closed.
+# Heartbeats are sent as usual
+transition_warmup_seconds = {transition_warmup_seconds}
+
+# StatsD server (host:port) to send metrics to over UDP: the gauges
+# aw_watcher_network.online, .latency_ms and .visible_networks, and the counters
+# .heartbeat_failures and .overrun_total.network/.wifi. Failing to send metrics
+# never affects event reporting
+# statsd_addr = "127.0.0.1:8125"
+
+# Create the buckets at startup. Disable if they are pre-provisioned on the server
+create_buckets = {create_buckets}
+
+# Milliseconds to wait before each retry when creating the buckets at startup
+# or sending the first heartbeat to a bucket fails, e.g. while aw-server is
+# still starting. Once the list is used up the request fails, unless
+# retry_schedule_repeat keeps retrying with the last delay
+retry_schedule_ms = [500, 1000, 2000]
+retry_schedule_repeat = {retry_schedule_repeat}
+
+# Send Wi-Fi scan and heartbeat errors as events to the diagnostics bucket
+emit_diagnostics = {emit_diagnostics}
+
+# Send an event with "from_ssid" and "to_ssid" fields to the Wi-Fi transitions
+# bucket each time the connected network changes, including connecting and
+# disconnecting. Needs the Wi-Fi watcher
+emit_wifi_transitions = {emit_wifi_transitions}
+
+# Check the system proxy on every poll and send an event with "from_proxy" and
+# "to_proxy" fields (null without a proxy) to the proxy changes bucket each time
+# it changes. The proxy comes from the network settings on macOS (including PAC
+# file URLs) and WinHTTP on Windows, and otherwise from the HTTPS_PROXY,
+# HTTP_PROXY and ALL_PROXY environment variables. Needs the network watcher
+track_proxy_changes = {track_proxy_changes}
+
+# Tools tried in order to scan for Wi-Fi networks on Linux until one works:
+# "nmcli" (NetworkManager), "iwlist" (Wireless Tools) and "iw"
+linux_wifi_backends = ["nmcli", "iwlist", "iw"]
+
+# iwlist and iw need root for a full scan. When not running as root, nmcli is
+# always tried before them, and with this set they aren't used at all
+require_unprivileged_scan = {require_unprivileged_scan}
+
+# Commands used to scan for Wi-Fi networks instead of the built-in tools.
+# The command is split on whitespace and its output must match the built-in tool's format
+# linux_scan_command = "nmcli -t device wifi list"
+# macos_scan_command = "system_profiler SPAirPortDataType"
+# windows_scan_command = "netsh wlan show networks"
+
+# Rename fields in the data of network and Wi-Fi events, e.g. to match what
+# existing dashboards expect. Two fields can't end up with the same name
+[field_names]
+# ssids = "networks"
+
+# Friendly names shown as the Wi-Fi event title instead of the raw SSID, which
+# is then kept in a "raw_ssid" field. SSIDs are matched case-insensitively.
+# Tables like this one must stay at the end of the file
+[ssid_labels]
+# "HG8145V5_ABCD" = "Home"
+
+# Polling intervals in seconds used instead of polling_interval while connected
+# to these Wi-Fi networks. Needs the Wi-Fi watcher. SSIDs are matched
+# case-insensitively
+[ssid_polling_intervals]
+# "HG8145V5_ABCD" = 30
+
+# Fixed values added to the data of every network and Wi-Fi event, e.g. to
+# filter events from many machines on the server. Tags never replace the
+# watcher's own fields. Keys are lowercased when the config is loaded
+[static_tags]
+# site = "berlin"
+# device_class = "laptop"
+
+# Settings for one Wi-Fi network each, as an alternative to the ssid_labels
+# and ssid_polling_intervals tables. An SSID can only be configured in one
+# place. With tracked = false the network is left out of the scanned SSIDs and
+# shows up as "<untracked>" while connected; it then can't have the other settings
+# [[networks]]
+# ssid = "HG8145V5_ABCD"
+# label = "Home"
+# polling_interval = 30
+#
+# [[networks]]
+# ssid = "Airport Free WiFi"
+# tracked = false
+"##,
+        polling_interval = defaults.polling_interval,
+        wifi_scan_interval = defaults.wifi_scan_interval,
+        startup_delay_ms = defaults.startup_delay_ms,
+        default_probe_port = defaults.default_probe_port,
+        connect_timeout_ms = defaults.connect_timeout_ms,
+        connectivity_mode = defaults.connectivity_mode,
+        dns_cache_ttl_seconds = defaults.dns_cache_ttl_seconds,
+        check_ipv6 = defaults.check_ipv6,
+        include_ssid_in_network_event = defaults.include_ssid_in_network_event,
+        include_default_route = defaults.include_default_route,
+        latency_window = defaults.latency_window,
+        include_link_speed = defaults.include_link_speed,
+        include_phy_mode = defaults.include_phy_mode,
+        include_scan_tool = defaults.include_scan_tool,
+        suppress_repeated_empty_wifi = defaults.suppress_repeated_empty_wifi,
+        report_hidden_ssids = defaults.report_hidden_ssids,
+        wifi_event_granularity = defaults.wifi_event_granularity,
+        include_ip_addresses = defaults.include_ip_addresses,
+        include_subnet = defaults.include_subnet,
+        include_host_info = defaults.include_host_info,
+        include_session_id = defaults.include_session_id,
+        include_airplane_mode = defaults.include_airplane_mode,
+        include_dns_servers = defaults.include_dns_servers,
+        include_connection_type = defaults.include_connection_type,
+        include_is_wifi = defaults.include_is_wifi,
+        include_gateway = defaults.include_gateway,
+        embed_timestamp = defaults.embed_timestamp,
+        accurate_durations = defaults.accurate_durations,
+        event_mode = defaults.event_mode,
+        mqtt_topic = defaults.mqtt_topic,
+        dedup_network_events = defaults.dedup_network_events,
+        max_heartbeat_gap_seconds = defaults.max_heartbeat_gap_seconds,
+        keepalive_interval_seconds = defaults.keepalive_interval_seconds,
+        align_to_clock = defaults.align_to_clock,
+        report_degraded = defaults.report_degraded,
+        include_quality = defaults.include_quality,
+        prioritize_reliable_targets = defaults.prioritize_reliable_targets,
+        notify_on_state_change = defaults.notify_on_state_change,
+        transition_warmup_seconds = defaults.transition_warmup_seconds,
+        include_uptime_stats = defaults.include_uptime_stats,
+        include_previous_state_duration = defaults.include_previous_state_duration,
+        include_stability_counter = defaults.include_stability_counter,
+        include_timing_stats = defaults.include_timing_stats,
+        enable_network_watcher = defaults.enable_network_watcher,
+        enable_wifi_watcher = defaults.enable_wifi_watcher,
+        require_unprivileged_scan = defaults.require_unprivileged_scan,
+        wifi_autodisable = defaults.wifi_autodisable,
+        create_buckets = defaults.create_buckets,
+        retry_schedule_repeat = defaults.retry_schedule_repeat,
+        emit_diagnostics = defaults.emit_diagnostics,
+        emit_wifi_transitions = defaults.emit_wifi_transitions,
+        track_proxy_changes = defaults.track_proxy_changes,
+    )
+}
+
+/// Write the annotated default config to the config path, refusing to
+/// overwrite an existing file unless `force` is set
+fn generate_config(force: bool) -> Result<PathBuf, String> {
+    let config_dir = watcher_config_dir()
+        .ok_or_else(|| "Could not determine the config directory".to_string())?;
+    let config_file = config_dir.join("config.toml");
+
+    if config_file.exists() && !force {
+        return Err(format!(
+            "{} already exists, use --force to overwrite it",
+            config_file.display()
+        ));
+    }
+
+    create_dir_all(&config_dir)
+        .map_err(|e| format!("Failed to create {}: {}", config_dir.display(), e))?;
+    write(&config_file, annotated_default_config())
+        .map_err(|e| format!("Failed to write {}: {}", config_file.display(), e))?;
+
+    Ok(config_file)
+}
+
+/// List the `.toml` files in a drop-in directory in sorted order.
+/// A missing or unreadable directory yields no files.
+fn drop_in_config_files(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = read_dir(dir) else {
+        return Vec::new();
+    };
+
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "toml"))
+        .collect();
+    files.sort();
+    files
+}
+
+fn main() {
+    // Parse command-line arguments (handles --help and --version)
+    let cli = Cli::parse();
+
+    if cli.generate_config {
+        match generate_config(cli.force) {
+            Ok(path) => {
+                println!("Wrote config file to {}", path.display());
+                return;
+            }
+            Err(e) => {
+                eprintln!("Error generating config: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if cli.config_schema {
+        let schema = schemars::schema_for!(AppConfig);
+        match serde_json::to_string_pretty(&schema) {
+            Ok(schema) => {
+                println!("{}", schema);
+                return;
+            }
+            Err(e) => {
+                eprintln!("Error generating config schema: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // The server checks run before the config is fully loaded, but need server_base_path
+    let server_base_path = || {
+        AppConfig::new(true)
+            .unwrap_or_default()
+            .server_base_path()
+            .unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            })
+    };
+
+    if cli.check_server {
+        match check_server(server_base_path().as_deref()) {
+            Ok(()) => return,
+            Err(e) => {
+                eprintln!("Server check failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if cli.selftest {
+        match selftest(server_base_path().as_deref()) {
+            Ok(()) => {
+                println!("Self-test passed");
+                return;
+            }
+            Err(e) => {
+                eprintln!("Self-test failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if cli.test_fast || env::var(TEST_FAST_ENV).is_ok_and(|value| value == "1") {
+        TEST_FAST.store(true, Ordering::Relaxed);
+        println!(
+            "Test mode: all intervals shortened to {}ms. Not meant for regular use",
+            TEST_FAST_INTERVAL.as_millis()
+        );
+    }
+
+    if let Some(seconds) = cli.duration {
+        DEADLINE
+            .set(Instant::now() + Duration::from_secs(seconds))
+            .ok();
+        println!("Stopping after {} seconds", seconds);
+    }
+
+    // Load configuration
+    let mut config = match AppConfig::new(cli.read_only_config) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error loading configuration: {}", e);
+
+            AppConfig::default()
+        }
+    };
+
+    if let Err(e) = config.apply_networks() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    if cli.debug_wifi {
+        if debug_wifi(&config) {
+            return;
+        }
+        std::process::exit(1);
+    }
+
+    if config.enable_wifi_watcher
+        && config.wifi_autodisable
+        && has_wireless_interface() == Some(false)
+    {
+        println!(
+            "No wireless interface found, disabling the Wi-Fi watcher (set wifi_autodisable = false to keep it)"
+        );
+        config.enable_wifi_watcher = false;
+    }
+
+    if config.enable_wifi_watcher
+        && config.wifi_autodisable
+        && config.scan_command().is_none()
+        && let Some(install_hint) = missing_wifi_scan_tools()
+    {
+        eprintln!(
+            "No Wi-Fi scanning tool found, disabling the Wi-Fi watcher and only monitoring connectivity. {} (set wifi_autodisable = false to keep it)",
+            install_hint
+        );
+        config.enable_wifi_watcher = false;
+    }
+
+    if !config.enable_network_watcher && !config.enable_wifi_watcher {
+        eprintln!(
+            "Both enable_network_watcher and enable_wifi_watcher are disabled, nothing to watch"
+        );
+        std::process::exit(1);
+    }
+
+    let unknown_outputs = config.unknown_outputs();
+    if !unknown_outputs.is_empty() {
+        eprintln!(
+            "Unknown outputs in config: {} (expected \"{}\", \"{}\" or \"{}\")",
+            unknown_outputs.join(", "),
+            OUTPUT_AW,
+            OUTPUT_FILE_JSONL,
+            OUTPUT_MQTT
+        );
+        std::process::exit(1);
+    }
+
+    if let Err(e) = config.server_base_path() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    if config.has_output(OUTPUT_MQTT) && config.mqtt_address().is_none() {
+        eprintln!(
+            "The mqtt output needs mqtt_broker set to a host or host:port, e.g. \"homeassistant.local:1883\""
+        );
+        std::process::exit(1);
+    }
+
+    let unknown_backends = config.unknown_linux_wifi_backends();
+    if !unknown_backends.is_empty() {
+        eprintln!(
+            "Unknown Linux Wi-Fi backends in config: {} (expected any of {})",
+            unknown_backends.join(", "),
+            LINUX_WIFI_BACKENDS.join(", ")
+        );
+        std::process::exit(1);
+    }
+
+    if !config.latency_buckets.is_empty()
+        && (config.latency_buckets[0] == 0
+            || config
+                .latency_buckets
+                .windows(2)
+                .any(|pair| pair[0] >= pair[1]))
+    {
+        eprintln!(
+            "latency_buckets must be increasing upper bounds greater than 0, e.g. [10, 50, 100, 250]"
+        );
+        std::process::exit(1);
+    }
+
+    if let Some(state) = &config.initial_state
+        && !INITIAL_STATES.contains(&state.as_str())
+    {
+        eprintln!(
+            "Unknown initial_state '{}' (expected one of: {})",
+            state,
+            INITIAL_STATES.join(", ")
+        );
+        std::process::exit(1);
+    }
+
+    if ![EVENT_MODE_HEARTBEAT, EVENT_MODE_TRANSITIONS].contains(&config.event_mode.as_str()) {
+        eprintln!(
+            "Unknown event_mode '{}' (expected \"{}\" or \"{}\")",
+            config.event_mode, EVENT_MODE_HEARTBEAT, EVENT_MODE_TRANSITIONS
+        );
+        std::process::exit(1);
+    }
+
+    if ![WIFI_GRANULARITY_AGGREGATE, WIFI_GRANULARITY_PER_NETWORK]
+        .contains(&config.wifi_event_granularity.as_str())
+    {
+        eprintln!(
+            "Unknown wifi_event_granularity '{}' (expected \"{}\" or \"{}\")",
+            config.wifi_event_granularity, WIFI_GRANULARITY_AGGREGATE, WIFI_GRANULARITY_PER_NETWORK
+        );
+        std::process::exit(1);
+    }
+
+    if config.targets_per_poll == Some(0) {
+        eprintln!("targets_per_poll must be at least 1");
+        std::process::exit(1);
+    }
+
+    if ![
+        CONNECTIVITY_MODE_TCP,
+        CONNECTIVITY_MODE_PING,
+        CONNECTIVITY_MODE_SCRIPT,
+    ]
+    .contains(&config.connectivity_mode.as_str())
+    {
+        eprintln!(
+            "Unknown connectivity_mode '{}' (expected \"{}\", \"{}\" or \"{}\")",
+            config.connectivity_mode,
+            CONNECTIVITY_MODE_TCP,
+            CONNECTIVITY_MODE_PING,
+            CONNECTIVITY_MODE_SCRIPT
+        );
+        std::process::exit(1);
+    }
+    if config.connectivity_mode == CONNECTIVITY_MODE_SCRIPT && config.connectivity_script.is_none()
+    {
+        eprintln!(
+            "connectivity_mode is \"{}\" but no connectivity_script is set",
+            CONNECTIVITY_MODE_SCRIPT
+        );
+        std::process::exit(1);
+    }
+
+    if let Some(interval) = config.wifi_scan_interval_docked
+        && (interval == 0 || interval > MAX_INTERVAL_SECONDS)
+    {
+        eprintln!(
+            "Invalid wifi_scan_interval_docked of {}s (must be between 1 and {})",
+            interval, MAX_INTERVAL_SECONDS
+        );
+        std::process::exit(1);
+    }
+
+    if let Some(interval) = config.offline_polling_interval
+        && (interval == 0 || interval > MAX_INTERVAL_SECONDS)
+    {
+        eprintln!(
+            "Invalid offline_polling_interval of {}s (must be between 1 and {})",
+            interval, MAX_INTERVAL_SECONDS
+        );
+        std::process::exit(1);
+    }
+
+    if let Some(interval) = config.probe_interval
+        && (interval == 0 || interval > MAX_INTERVAL_SECONDS)
+    {
+        eprintln!(
+            "Invalid probe_interval of {}s (must be between 1 and {})",
+            interval, MAX_INTERVAL_SECONDS
+        );
+        std::process::exit(1);
+    }
+
+    for (field, value, default) in config.reset_invalid_intervals() {
+        eprintln!(
+            "Invalid {} of {}s (must be between 1 and {}), using the default of {}s",
+            field, value, MAX_INTERVAL_SECONDS, default
+        );
+    }
+
+    for (target, bracketed) in config.bracket_ipv6_targets() {
+        eprintln!(
+            "Probe target '{}' is ambiguous: IPv6 addresses need brackets around them, using '{}'",
+            target, bracketed
+        );
+    }
+
+    let reserved_tags = config.reserved_static_tags();
+    if !reserved_tags.is_empty() {
+        eprintln!(
+            "Warning: static_tags {} have the same name as event fields and will be ignored",
+            reserved_tags.join(", ")
+        );
+    }
+
+    if let Some(threshold) = config.quality_online_threshold
+        && !(threshold > 0.0 && threshold <= 1.0)
+    {
+        eprintln!(
+            "quality_online_threshold must be more than 0.0 and at most 1.0, got {}",
+            threshold
+        );
+        std::process::exit(1);
+    }
+
+    if let Err(e) = config.validate_target_timeouts() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    if let Err(e) = config.validate_field_names() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    let active_hours = match config.active_hours() {
+        Ok(active_hours) => active_hours,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let polling_interval = config.polling_interval;
+
+    // Get hostname and create bucket ID with hostname appended
+    let hostname = match get_hostname() {
+        Ok(name) => name.to_string_lossy().into_owned(),
+        Err(_) => "unknown-host".to_string(),
+    };
+
+    let user = env::var("USER")
+        .or_else(|_| env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown-user".to_string());
+    let interface = if config.network_bucket_id.is_some() || config.wifi_bucket_id.is_some() {
+        default_route_interface().unwrap_or_else(|| "unknown".to_string())
+    } else {
+        String::new()
+    };
+    let placeholders = [
+        ("hostname", hostname.as_str()),
+        ("user", user.as_str()),
+        ("os", env::consts::OS),
+        ("iface", interface.as_str()),
+    ];
+    let bucket_id_from = |template: &Option<String>, default: String| match template {
+        Some(template) => expand_bucket_template(template, &placeholders).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }),
+        None => default,
+    };
+
+    let bucket_id = bucket_id_from(
+        &config.network_bucket_id,
+        format!("aw-watcher-network_{}", hostname),
+    );
+    let wifi_bucket_id = bucket_id_from(
+        &config.wifi_bucket_id,
+        format!("aw-watcher-wifi_{}", hostname),
+    );
+    let diagnostics_bucket_id = format!("aw-watcher-network-diag_{}", hostname);
+    let wifi_transitions_bucket_id = format!("aw-watcher-wifi-transitions_{}", hostname);
+    let proxy_bucket_id = format!("aw-watcher-network-proxy_{}", hostname);
+    let event_type = "network-status";
+    let wifi_event_type = "wifi-status";
+    let wifi_transitions_event_type = "wifi-transition";
+    let proxy_event_type = "proxy-change";
+    let diagnostics_event_type = "watcher-diagnostics";
+
+    if let Some(old_id) = &cli.merge_bucket {
+        let current_buckets = [
+            (bucket_id.as_str(), event_type),
+            (wifi_bucket_id.as_str(), wifi_event_type),
+        ];
+        // Validated at startup
+        let base_path = config.server_base_path().ok().flatten();
+        match merge_bucket(
+            base_path.as_deref(),
+            old_id,
+            &current_buckets,
+            cli.delete_old,
+        ) {
+            Ok(()) => return,
+            Err(e) => {
+                eprintln!("Bucket merge failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    println!(
+        "Starting aw-watcher-network-rs with polling interval of {} seconds",
+        polling_interval
+    );
+    println!(
+        "Wi-Fi SSID scanning interval: {} seconds",
+        config.wifi_scan_interval
+    );
+    if let Some(docked_interval) = config.wifi_scan_interval_docked {
+        println!(
+            "Wi-Fi SSID scanning interval on AC power: {} seconds",
+            docked_interval
+        );
+    }
+    if let Some(probe_interval) = config.probe_interval {
+        println!(
+            "Probing connectivity every {} seconds",
+            probe_interval.min(polling_interval)
+        );
+    }
+    if let Some(window) = &config.active_hours {
+        println!("Only polling during active hours: {}", window);
+    }
+    if let Some(offline_interval) = config.offline_polling_interval {
+        println!(
+            "Polling interval while persistently offline: {} seconds",
+            offline_interval
+        );
+    }
+    if config.enable_network_watcher {
+        println!("Using bucket ID: {}", bucket_id);
+    } else {
+        println!("Network watcher disabled");
+    }
+    if config.enable_wifi_watcher {
+        println!("Using Wi-Fi bucket ID: {}", wifi_bucket_id);
+    } else {
+        println!("Wi-Fi watcher disabled");
+    }
+    if config.emit_diagnostics {
+        println!("Using diagnostics bucket ID: {}", diagnostics_bucket_id);
+    }
+    let emit_wifi_transitions = config.enable_wifi_watcher && config.emit_wifi_transitions;
+    if emit_wifi_transitions {
+        println!(
+            "Using Wi-Fi transitions bucket ID: {}",
+            wifi_transitions_bucket_id
+        );
+    }
+    let track_proxy_changes = config.enable_network_watcher && config.track_proxy_changes;
+    if track_proxy_changes {
+        println!("Using proxy changes bucket ID: {}", proxy_bucket_id);
+    }
+
+    if config.enable_wifi_watcher
+        && let Some(command) = config.scan_command()
+    {
+        validate_scan_command(command);
+    }
+
+    println!("Sending events to: {}", config.outputs.join(", "));
+
+    // Exit if the aw-server client can't be created
+    let sinks = EventSinks::new(&config).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+
+    // Create or get buckets, unless they are expected to exist already
+    if let Some(client) = &sinks.client {
+        let client = client.lock().unwrap();
+        if config.create_buckets {
+            let retry_schedule = RetrySchedule::from_config(&config);
+            let create = |bucket_id: &str, event_type: &str| {
+                retry_schedule.run(&format!("Creating bucket {}", bucket_id), || {
+                    create_bucket(&client, bucket_id, event_type)
+                })
+            };
+
+            if config.enable_network_watcher {
+                create(&bucket_id, event_type).expect("Failed to create network bucket");
+            }
+
+            if config.enable_wifi_watcher {
+                create(&wifi_bucket_id, wifi_event_type).expect("Failed to create Wi-Fi bucket");
+            }
+
+            if config.emit_diagnostics {
+                create(&diagnostics_bucket_id, diagnostics_event_type)
+                    .expect("Failed to create diagnostics bucket");
+            }
+
+            if emit_wifi_transitions {
+                create(&wifi_transitions_bucket_id, wifi_transitions_event_type)
+                    .expect("Failed to create Wi-Fi transitions bucket");
+            }
+
+            if track_proxy_changes {
+                create(&proxy_bucket_id, proxy_event_type)
+                    .expect("Failed to create proxy changes bucket");
+            }
+        } else {
+            println!("Skipping bucket creation, assuming the buckets already exist");
+        }
+    }
+
+    // Shared by the network loop, the Wi-Fi thread and diagnostics
+    let sinks: Arc<dyn EventSink> = Arc::new(sinks);
+
+    let wifi_state: Arc<Mutex<WifiState>> = Arc::new(Mutex::new(WifiState::default()));
+
+    let diagnostics: Option<Arc<Diagnostics>> = if config.emit_diagnostics {
+        Some(Arc::new(Diagnostics::new(
+            Arc::clone(&sinks),
+            diagnostics_bucket_id,
+        )))
+    } else {
+        None
+    };
+
+    if config.include_host_info {
+        let mut host_info = Map::new();
+        host_info.insert("host".to_string(), Value::String(hostname.clone()));
+        host_info.insert("os".to_string(), Value::from(env::consts::OS));
+        if let Some(version) = os_version() {
+            host_info.insert("os_version".to_string(), Value::String(version));
+        }
+        HOST_INFO.set(host_info).ok();
+    }
+    if config.include_session_id {
+        let session_id = Uuid::new_v4().to_string();
+        println!("Session ID: {}", session_id);
+        SESSION_ID.set(session_id).ok();
+    }
+
+    // Cover the time until the first probe, extended once the probe completes
+    let mut initial_event = config
+        .initial_state
+        .as_ref()
+        .filter(|_| config.enable_network_watcher)
+        .map(|state| {
+            let mut data_map = Map::new();
+            data_map.insert("title".to_string(), Value::String(state.clone()));
+            add_host_info(&mut data_map);
+            let event = Event {
+                id: None,
+                timestamp: Utc::now(),
+                duration: TimeDelta::zero(),
+                data: config.add_static_tags(data_map),
+            };
+            let target_bucket = config.bucket_id_for_today(&bucket_id);
+            if let Err(e) = sinks.send(&target_bucket, &event, 0.0) {
+                eprintln!("Error sending initial state: {}", e);
+            }
+            (target_bucket, event, Instant::now())
+        });
+
+    if config.startup_delay_ms > 0 {
+        println!(
+            "Waiting {}ms before the first check",
+            config.startup_delay_ms
+        );
+        sleep_until_deadline(Duration::from_millis(config.startup_delay_ms));
+    }
+
+    // Start Wi-Fi SSID scanning thread on supported platforms
+    let wifi_handle = if config.enable_wifi_watcher {
+        let wifi_config = config.clone();
+        let wifi_sinks = Arc::clone(&sinks);
+        let wifi_bucket = wifi_bucket_id.clone();
+        let transitions_bucket = emit_wifi_transitions.then(|| wifi_transitions_bucket_id.clone());
+        let wifi_state = Arc::clone(&wifi_state);
+        let wifi_diagnostics = diagnostics.clone();
+
+        Some(thread::spawn(move || {
+            wifi_ssid_watcher(
+                wifi_config,
+                wifi_sinks,
+                wifi_bucket,
+                wifi_event_type,
+                transitions_bucket,
+                wifi_state,
+                wifi_diagnostics,
+            );
+        }))
+    } else {
+        None
+    };
+
+    // Without the network watcher the Wi-Fi thread does all the work, so keep
+    // the process alive for as long as it runs
+    if !config.enable_network_watcher {
+        if let Some(handle) = wifi_handle
+            && handle.join().is_err()
+        {
+            eprintln!("Wi-Fi watcher thread panicked");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    RESOLVER
+        .set(Resolver::new(Duration::from_secs(
+            config.dns_cache_ttl_seconds,
+        )))
+        .ok();
+    CONNECT_TIMEOUT
+        .set(Duration::from_millis(config.connect_timeout_ms))
+        .ok();
+    if config.connectivity_mode == CONNECTIVITY_MODE_PING {
+        match PingConnector::new() {
+            Ok(connector) => {
+                PING_CONNECTOR.set(connector).ok();
+            }
+            Err(e) => eprintln!(
+                "Warning: can't send ICMP pings ({}), probing over TCP instead. Ping needs root on Linux unless your group is allowed by net.ipv4.ping_group_range, and admin rights on Windows",
+                e
+            ),
+        }
+    }
+
+    let connectivity_targets = config.connectivity_targets();
+    // Validated at startup to be set in script mode
+    let connectivity_script = config
+        .connectivity_script
+        .as_deref()
+        .filter(|_| config.connectivity_mode == CONNECTIVITY_MODE_SCRIPT);
+    if let Some(script) = connectivity_script {
+        println!("Checking connectivity with script: {}", script);
+    } else {
+        println!(
+            "Probing connectivity against: {}",
+            connectivity_targets.join(", ")
+        );
+    }
+    if let Some(target) = &config.primary_target {
+        println!("Reporting primary target reachability for: {}", target);
+    }
+    if let Some(target) = &config.lan_probe_target {
+        println!("Probing LAN reachability against: {}", target);
+    }
+
+    // Number of polls in a row that found the network offline
+    let mut consecutive_offline: u32 = 0;
+    // State reported by the previous probe (online, degraded), and when the last heartbeat was sent
+    let mut last_status: Option<(bool, bool)> = None;
+    let mut last_heartbeat: Option<Instant> = None;
+    // Time covered by probes and how much of it was online, for the online ratio.
+    // Each stretch between two probes counts towards the state the first one found
+    let mut tracked_time = Duration::ZERO;
+    let mut online_time = Duration::ZERO;
+    let mut previous_probe: Option<(Instant, bool)> = None;
+    // Latencies of the most recent successful probes, oldest first
+    let mut recent_latencies: VecDeque<Duration> = VecDeque::with_capacity(config.latency_window);
+
+    let statsd = Statsd::from_config(&config);
+    let mut target_health = config
+        .prioritize_reliable_targets
+        .then(TargetHealth::default);
+
+    if config.notify_on_state_change && !cfg!(feature = "notify") {
+        eprintln!(
+            "notify_on_state_change is enabled, but this build doesn't include the notify feature"
+        );
+    }
+    // In transitions mode, the event of the current state, sent once the state changes
+    let transitions_mode = config.event_mode == EVENT_MODE_TRANSITIONS;
+    let mut open_period: Option<Event> = None;
+
+    // Polls in a row that found the current online/offline state
+    let mut consecutive_same_state: u64 = 0;
+
+    // When the current online/offline state started, for previous_state_duration_seconds
+    let mut online_state_since: Option<Instant> = None;
+
+    // When the current outage started, for the notification on reconnect
+    let mut offline_since: Option<Instant> = None;
+    // State changes before this point are not notified or sent as transitions
+    let warmup_end = Instant::now() + Duration::from_secs(config.transition_warmup_seconds);
+
+    // Polls that took longer than the polling interval
+    let mut overrun_total: u64 = 0;
+    // Last event sent, its bucket and pulsetime, for the final event at shutdown
+    let mut last_sent: Option<(String, Event, u64)> = None;
+    // System proxy found by the previous poll, once there has been one
+    let mut last_proxy: Option<Option<String>> = None;
+
+    // Main loop to check network status periodically
+    loop {
+        if shutdown_due() {
+            break;
+        }
+
+        if wait_for_active_hours(active_hours, "network") {
+            // Don't count the pause towards the online ratio
+            previous_probe = None;
+            continue;
+        }
+
+        // Record the start time of this iteration
+        let loop_start = Instant::now();
+        let warmed_up = loop_start >= warmup_end;
+
+        if track_proxy_changes {
+            let proxy = system_proxy();
+            if warmed_up
+                && let Some(previous) = &last_proxy
+                && *previous != proxy
+            {
+                send_proxy_change(
+                    &config,
+                    sinks.as_ref(),
+                    &proxy_bucket_id,
+                    previous.as_deref(),
+                    proxy.as_deref(),
+                );
+            }
+            last_proxy = Some(proxy);
+        }
+
+        // A random subset of the targets with targets_per_poll
+        let poll_targets: Vec<String> = match config.targets_per_poll {
+            Some(count) if count < connectivity_targets.len() => connectivity_targets
+                .choose_multiple(&mut rand::thread_rng(), count)
+                .cloned()
+                .collect(),
+            _ => connectivity_targets.clone(),
+        };
+
+        let mut script_output = None;
+        let (probe, quality) = if let Some(script) = connectivity_script {
+            let (probe, output) =
+                run_connectivity_script(script, Duration::from_millis(config.connect_timeout_ms));
+            script_output = output;
+            (probe, None)
+        } else if config.include_quality || config.quality_online_threshold.is_some() {
+            let (probe, quality) = check_connectivity_quality(&poll_targets);
+            // Below the threshold the poll counts as offline
+            let probe = probe.filter(|_| {
+                config
+                    .quality_online_threshold
+                    .is_none_or(|threshold| quality >= threshold)
+            });
+            (probe, Some(quality))
+        } else if let Some(target_health) = &mut target_health {
+            (target_health.probe(&poll_targets), None)
+        } else {
+            (check_network_connectivity(&poll_targets), None)
+        };
+        let degraded_reason = if config.report_degraded {
+            check_degraded_connectivity(&connectivity_targets)
+        } else {
+            None
+        };
+        // A degraded network still counts as online, even when the configured targets failed
+        let status = probe.is_some() || degraded_reason.is_some();
+
+        // Extend the initial state up to now; the real state starts here
+        if let Some((target_bucket, mut event, sent)) = initial_event.take() {
+            event.timestamp = Utc::now();
+            let pulsetime = sent.elapsed().as_secs_f64() + 1.0;
+            if let Err(e) = sinks.send(&target_bucket, &event, pulsetime) {
+                eprintln!("Error sending initial state: {}", e);
+            }
+        }
+
+        if let Some(statsd) = &statsd {
+            statsd.gauge("online", if status { 1.0 } else { 0.0 });
+            if let Some(probe) = probe {
+                statsd.gauge("latency_ms", probe.latency.as_secs_f64() * 1000.0);
+            }
+            if let Some(target_health) = &target_health {
+                target_health.report(statsd);
+            }
+        }
+
+        if let Some((probed_at, was_online)) = previous_probe {
+            let stretch = loop_start.duration_since(probed_at);
+            tracked_time += stretch;
+            if was_online {
+                online_time += stretch;
+            }
+        }
+        previous_probe = Some((loop_start, status));
+
+        if config.latency_window > 0
+            && let Some(probe) = probe
+        {
+            if recent_latencies.len() == config.latency_window {
+                recent_latencies.pop_front();
+            }
+            recent_latencies.push_back(probe.latency);
+        }
+
+        if status {
+            consecutive_offline = 0;
+        } else {
+            consecutive_offline = consecutive_offline.saturating_add(1);
+        }
+
+        // Stretch the interval while persistently offline, revert as soon as we're back online
+        let interval = match config.offline_polling_interval {
+            Some(offline_interval) if consecutive_offline >= SUSTAINED_OFFLINE_POLLS => {
+                offline_interval
+            }
+            _ => {
+                let connected_ssid = wifi_state.lock().unwrap().connected_ssid.clone();
+                config.polling_interval_for(connected_ssid.as_deref())
+            }
+        };
+
+        // Send a heartbeat every polling interval, or immediately when the state changes
+        let state = (status, degraded_reason.is_some());
+        let state_changed = last_status != Some(state);
+        let heartbeat_due = last_heartbeat
+            .is_none_or(|sent: Instant| sent.elapsed() >= interval_duration(interval));
+        let online_changed = last_status.is_some_and(|(was_online, _)| was_online != status);
+        let keepalive_due = config.keepalive_interval_seconds > 0
+            && last_heartbeat.is_some_and(|sent| {
+                sent.elapsed() >= interval_duration(config.keepalive_interval_seconds)
+            });
+        last_status = Some(state);
+
+        let previous_state_duration = online_state_since
+            .filter(|_| online_changed && warmed_up)
+            .map(|since| loop_start.duration_since(since));
+        if online_changed || online_state_since.is_none() {
+            online_state_since = Some(loop_start);
+        }
+        consecutive_same_state = if online_changed {
+            1
+        } else {
+            consecutive_same_state.saturating_add(1)
+        };
+
+        if !status && offline_since.is_none() {
+            offline_since = Some(loop_start);
+        }
+
+        if config.notify_on_state_change && online_changed && warmed_up {
+            if status {
+                let outage = offline_since.map_or(0, |since| since.elapsed().as_secs());
+                notify(
+                    "Back online",
+                    &format!(
+                        "The network was offline for {}m {}s",
+                        outage / 60,
+                        outage % 60
+                    ),
+                );
+            } else {
+                notify("Offline", "The network connection was lost");
+            }
+        }
+
+        let hook = if status {
+            &config.on_online_command
+        } else {
+            &config.on_offline_command
+        };
+        if online_changed
+            && warmed_up
+            && let Some(command) = hook
+        {
+            let outage = if status {
+                offline_since.map_or(0, |since| since.elapsed().as_secs())
+            } else {
+                0
+            };
+            let ssid = wifi_state.lock().unwrap().connected_ssid.clone();
+            run_transition_command(command, outage, ssid.as_deref().unwrap_or(""));
+        }
+
+        if status {
+            offline_since = None;
+        }
+
+        if state_changed || (heartbeat_due && !transitions_mode) {
+            // Before a full stretch has been tracked, go by the current state
+            let online_ratio = if tracked_time.is_zero() {
+                if status { 1.0 } else { 0.0 }
+            } else {
+                online_time.as_secs_f64() / tracked_time.as_secs_f64()
+            };
+            let info = gather_connection_info(
+                &config,
+                probe,
+                PollState {
+                    online: status,
+                    degraded_reason,
+                    script_output,
+                    quality,
+                    online_ratio,
+                    previous_state_duration,
+                    consecutive_same_state,
+                    overrun_total,
+                    recent_latencies: &recent_latencies,
+                    wifi_state: &wifi_state,
+                },
+            );
+
+            // Create and send event
+            let mut event = Event {
+                id: None,
+                timestamp: Utc::now(),
+                duration: event_duration(&config, interval, last_heartbeat, loop_start),
+                data: info.event_data(),
+            };
+
+            if config.embed_timestamp {
+                embed_timestamp(&mut event);
+            }
+            add_host_info(&mut event.data);
+            event.data = config.add_static_tags(config.rename_fields(event.data));
+
+            let target_bucket = config.bucket_id_for_today(&bucket_id);
+
+            // With dedup, an event with the same data as the last one is only resent
+            // once max_heartbeat_gap_seconds have passed. The longer pulsetime keeps
+            // aw-server merging the heartbeats across the skipped polls. Transitions
+            // mode only sends heartbeats for keepalives, see send_period
+            let pulsetime = if transitions_mode {
+                config.keepalive_interval_seconds
+            } else if config.dedup_network_events {
+                interval + config.max_heartbeat_gap_seconds
+            } else {
+                interval
+            };
+            let duplicate = config.dedup_network_events
+                && !state_changed
+                && !keepalive_due
+                && !transitions_mode
+                && last_sent
+                    .as_ref()
+                    .is_some_and(|(last_bucket, last_event, _)| {
+                        *last_bucket == target_bucket && last_event.data == event.data
+                    })
+                && last_heartbeat.is_some_and(|sent| {
+                    sent.elapsed() < interval_duration(config.max_heartbeat_gap_seconds)
+                });
+
+            if !duplicate {
+                let result = if target_bucket != bucket_id && config.create_buckets {
+                    sinks.ensure_bucket(&target_bucket, event_type)
+                } else {
+                    Ok(())
+                };
+
+                let result = result.and_then(|_| {
+                    if transitions_mode {
+                        // Close the previous state's event with the time it actually lasted.
+                        // During the warmup it is dropped, so the first period starts with
+                        // the state the network settled on
+                        match open_period.replace(event.clone()) {
+                            Some(mut previous) if warmed_up => {
+                                previous.duration = event.timestamp - previous.timestamp;
+                                send_period(sinks.as_ref(), &target_bucket, &previous, pulsetime)
+                            }
+                            _ => Ok(()),
+                        }
+                    } else {
+                        sinks.send(&target_bucket, &event, pulsetime as f64)
+                    }
+                });
+                last_sent = Some((target_bucket, event, pulsetime));
+
+                match result {
+                    Ok(_) => (),
+                    Err(e) => {
+                        eprintln!("Error sending heartbeat: {}", e);
+                        if let Some(statsd) = &statsd {
+                            statsd.count("heartbeat_failures", 1);
+                        }
+                        if let Some(diagnostics) = &diagnostics {
+                            diagnostics.report(
+                                "error",
+                                "network",
+                                &format!("Heartbeat failed: {}", e),
+                            );
+                        }
+                    }
+                }
+
+                last_heartbeat = Some(loop_start);
+            }
+        } else if transitions_mode
+            && keepalive_due
+            && let Some(mut period) = open_period.clone()
+            && let Some((period_bucket, _, pulsetime)) = &last_sent
+        {
+            period.duration = Utc::now() - period.timestamp;
+            if let Err(e) = send_period(sinks.as_ref(), period_bucket, &period, *pulsetime) {
+                eprintln!("Error sending keepalive heartbeat: {}", e);
+            }
+            last_heartbeat = Some(loop_start);
+        }
+
+        // Probe more often than we report if a separate probe interval is configured
+        let probe_interval = config
+            .probe_interval
+            .map_or(interval, |probe_interval| probe_interval.min(interval));
+
+        // Calculate how much time has elapsed in this iteration
+        let elapsed = loop_start.elapsed();
+
+        // Calculate the time to sleep to maintain consistent intervals
+        let overran = elapsed >= interval_duration(probe_interval);
+        if overran {
+            // Operations took longer than the interval, log a warning about the missed interval
+            eprintln!(
+                "Warning: Operations took longer than polling interval ({:?} > {}s)",
+                elapsed, probe_interval
+            );
+            overrun_total += 1;
+            if let Some(statsd) = &statsd {
+                statsd.count("overrun_total.network", 1);
+            }
+        }
+        if config.align_to_clock {
+            // After an overrun this skips the missed boundary rather than polling late
+            sleep_until_deadline(until_clock_boundary(interval_duration(probe_interval)));
+        } else if !overran {
+            sleep_until_deadline(interval_duration(probe_interval) - elapsed);
+        }
+    }
+
+    send_final_event(sinks.as_ref(), last_sent, transitions_mode);
+
+    if let Some(handle) = wifi_handle
+        && handle.join().is_err()
+    {
+        eprintln!("Wi-Fi watcher thread panicked");
+        std::process::exit(1);
+    }
+    println!("Reached the --duration limit, exiting");
+}
+
+/// Send a transitions mode period with its length so far. With keepalives
+/// (`keepalive_seconds` > 0) the period is already on aw-server from earlier
+/// sends, so it goes out as a heartbeat that extends that event. Otherwise it
+/// is inserted once it has ended
+fn send_period(
+    sinks: &dyn EventSink,
+    bucket_id: &str,
+    period: &Event,
+    keepalive_seconds: u64,
+) -> Result<(), String> {
+    if keepalive_seconds > 0 {
+        sinks.send(bucket_id, period, keepalive_seconds as f64)
+    } else {
+        sinks.insert_event(bucket_id, period)
+    }
+}
+
+/// At shutdown, stretch the last event up to now: a heartbeat with the same data
+/// merges into it, while a transitions period is inserted with its length so far
+fn send_final_event(
+    sinks: &dyn EventSink,
+    last_sent: Option<(String, Event, u64)>,
+    close_period: bool,
+) {
+    let Some((bucket_id, mut event, pulsetime)) = last_sent else {
+        return;
+    };
+
+    let result = if close_period {
+        event.duration = Utc::now() - event.timestamp;
+        send_period(sinks, &bucket_id, &event, pulsetime)
+    } else {
+        event.timestamp = Utc::now();
+        event.duration = TimeDelta::zero();
+        sinks.send(&bucket_id, &event, pulsetime as f64)
+    };
+
+    if let Err(e) = result {
+        eprintln!("Error sending final event: {}", e);
+    }
+}
+
+/// Everything reported about the connection in a network event. Optional
+/// fields are omitted from the event when unset
+#[derive(Debug, Default, Clone, Serialize)]
+struct ConnectionInfo {
+    /// Whether any connectivity target was reachable; reported as the title
+    #[serde(skip)]
+    online: bool,
+    /// Which probes failed when only one of the port 53 and port 443 probes worked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    degraded_reason: Option<String>,
+    /// What the connectivity script printed, in "script" connectivity mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    script_output: Option<String>,
+    /// Interface that carried the successful probe, if it was looked up
+    #[serde(skip)]
+    interface: Option<String>,
+    /// Connect time of the latest probe, in whole milliseconds unless latency_precision is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latency_ms: Option<Value>,
+    /// Average connect time over the latency window
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avg_latency_ms: Option<Value>,
+    /// Range of latency_buckets the latest connect time falls in, e.g. "10-50ms"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latency_bucket: Option<String>,
+    /// Fraction of the connectivity targets that were reachable
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quality: Option<f64>,
+    /// Whether the primary target was reachable
+    #[serde(skip_serializing_if = "Option::is_none")]
+    primary_reachable: Option<bool>,
+    /// Whether the LAN probe target was reachable
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lan_reachable: Option<bool>,
+    /// Whether any IPv6 target was reachable
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ipv6: Option<bool>,
+    /// SSID of the connected Wi-Fi network
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ssid: Option<String>,
+    /// Fraction of time online since the watcher started
+    #[serde(skip_serializing_if = "Option::is_none")]
+    online_ratio: Option<f64>,
+    /// How long the previous online/offline state lasted, on the event where it ended
+    #[serde(skip_serializing_if = "Option::is_none")]
+    previous_state_duration_seconds: Option<u64>,
+    /// Polls in a row the online/offline state has held, including this one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    consecutive_same_state: Option<u64>,
+    /// Polls since startup that took longer than the polling interval
+    #[serde(skip_serializing_if = "Option::is_none")]
+    overrun_total: Option<u64>,
+    /// Whether the probe went over the default route interface
+    #[serde(skip_serializing_if = "Option::is_none")]
+    is_default_route: Option<bool>,
+    /// IPv4 address of the interface that carried the probe
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ipv4_addr: Option<String>,
+    /// IPv6 address of the interface that carried the probe
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ipv6_addr: Option<String>,
+    /// IPv4 subnet of the interface that carried the probe, e.g. "192.168.1.0/24"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subnet: Option<String>,
+    /// Whether all radios are off
+    #[serde(skip_serializing_if = "Option::is_none")]
+    airplane_mode: Option<bool>,
+    /// DNS servers the system is configured to use
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_servers: Option<Vec<String>>,
+    /// "cellular", "wifi", "vpn" or "wired"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connection_type: Option<String>,
+    /// Whether the probe went over a Wi-Fi interface
+    #[serde(skip_serializing_if = "Option::is_none")]
+    is_wifi: Option<bool>,
+    /// IPv4 gateway of the default route
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gateway: Option<String>,
+    /// Speed of the wired interface that carried the probe
+    #[serde(skip_serializing_if = "Option::is_none")]
+    link_speed_mbps: Option<f64>,
+}
+
+impl ConnectionInfo {
+    /// Title shown in the timeline
+    fn title(&self) -> &'static str {
+        if self.degraded_reason.is_some() {
+            "degraded"
+        } else if self.online {
+            // the extra space serves a purpose in the coloring in the timeline view.
+            "online "
+        } else {
+            "offline"
+        }
+    }
+
+    /// Data map of the network event: the title plus every field that is set
+    fn event_data(&self) -> Map<String, Value> {
+        let mut data_map = match serde_json::to_value(self) {
+            Ok(Value::Object(map)) => map,
+            _ => Map::new(),
+        };
+        data_map.insert("title".to_string(), Value::String(self.title().to_string()));
+        data_map
+    }
+}
+
+/// A latency as reported in events: whole milliseconds, or rounded to
+/// latency_precision decimals
+fn latency_value(config: &AppConfig, latency: Duration) -> Value {
+    match config.latency_precision {
+        Some(decimals) => {
+            let scale = 10f64.powi(decimals as i32);
+            Value::from((latency.as_secs_f64() * 1000.0 * scale).round() / scale)
+        }
+        None => Value::from(latency.as_millis() as u64),
+    }
+}
+
+/// Name of the range of `bounds` (increasing upper bounds in ms) a latency falls
+/// in: "<10ms", "10-50ms", ... or ">=250ms" past the last bound
+fn latency_bucket(bounds: &[u64], latency: Duration) -> String {
+    let millis = latency.as_secs_f64() * 1000.0;
+    match bounds.iter().position(|&bound| millis < bound as f64) {
+        Some(0) => format!("<{}ms", bounds[0]),
+        Some(i) => format!("{}-{}ms", bounds[i - 1], bounds[i]),
+        None => format!(">={}ms", bounds[bounds.len() - 1]),
+    }
+}
+
+/// What the network loop knows about a poll besides the probe itself
+struct PollState<'a> {
+    /// Whether the poll counts as online: the probe succeeded or the network is degraded
+    online: bool,
+    degraded_reason: Option<String>,
+    script_output: Option<String>,
+    quality: Option<f64>,
+    /// Fraction of time online since the watcher started
+    online_ratio: f64,
+    /// How long the previous online/offline state lasted, if it ended with this poll
+    previous_state_duration: Option<Duration>,
+    consecutive_same_state: u64,
+    overrun_total: u64,
+    /// Latencies of the recent probes, for the average over the latency window
+    recent_latencies: &'a VecDeque<Duration>,
+    /// Connected network found by the Wi-Fi thread
+    wifi_state: &'a Mutex<WifiState>,
+}
+
+/// Build the network event's details: what the config enables about the
+/// connection the probe found, together with the loop's state for this poll
+fn gather_connection_info(
+    config: &AppConfig,
+    probe: Option<ProbeSuccess>,
+    poll: PollState,
+) -> ConnectionInfo {
+    let mut info = ConnectionInfo {
+        online: poll.online,
+        degraded_reason: poll.degraded_reason,
+        script_output: poll.script_output,
+        ..ConnectionInfo::default()
+    };
+
+    if config.include_quality {
+        info.quality = poll.quality;
+    }
+
+    if config.include_uptime_stats {
+        info.online_ratio = Some(poll.online_ratio);
+    }
+
+    if config.include_previous_state_duration {
+        info.previous_state_duration_seconds = poll
+            .previous_state_duration
+            .map(|duration| duration.as_secs());
+    }
+
+    if config.include_stability_counter {
+        info.consecutive_same_state = Some(poll.consecutive_same_state);
+    }
+
+    if config.include_timing_stats {
+        info.overrun_total = Some(poll.overrun_total);
+    }
+
+    if config.latency_window > 0
+        && let Some(probe) = probe
+    {
+        info.latency_ms = Some(latency_value(config, probe.latency));
+        if !config.latency_buckets.is_empty() {
+            info.latency_bucket = Some(latency_bucket(&config.latency_buckets, probe.latency));
+        }
+
+        // Average over the window (or as much of it as is filled)
+        let recent_latencies = poll.recent_latencies;
+        let average =
+            recent_latencies.iter().sum::<Duration>() / recent_latencies.len().max(1) as u32;
+        info.avg_latency_ms = Some(latency_value(config, average));
+    }
+
+    if config.include_ssid_in_network_event {
+        info.ssid = poll.wifi_state.lock().unwrap().connected_ssid.clone();
+    }
+
+    if let Some(target) = &config.primary_target {
+        info.primary_reachable = Some(probe_tcp(std::slice::from_ref(target)).is_some());
+    }
+
+    if let Some(target) = &config.lan_probe_target {
+        info.lan_reachable = Some(probe_tcp(std::slice::from_ref(target)).is_some());
+    }
+
+    if config.include_airplane_mode {
+        info.airplane_mode = airplane_mode();
+    }
+
+    if config.include_dns_servers {
+        info.dns_servers = dns_servers();
+    }
+
+    if config.check_ipv6 {
+        info.ipv6 = Some(check_ipv6_connectivity(config.default_probe_port));
+    }
+
+    if config.include_gateway {
+        info.gateway = default_gateway().map(|gateway| gateway.to_string());
+    }
+
+    // Interface that carried the successful probe, only looked up when needed
+    if config.include_default_route
+        || config.include_link_speed
+        || config.include_connection_type
+        || config.include_is_wifi
+        || config.include_ip_addresses
+        || config.include_subnet
+    {
+        info.interface = probe
+            .and_then(|probe| probe.local_addr)
+            .and_then(|local_addr| interface_for_ip(local_addr.ip()));
+    }
+
+    if let Some(interface) = &info.interface {
+        // Omitted when the default route can't be determined
+        if config.include_default_route {
+            info.is_default_route =
+                default_route_interface().map(|default_interface| *interface == default_interface);
+        }
+
+        if config.include_ip_addresses {
+            let (ipv4_addr, ipv6_addr) = interface_addresses(interface);
+            info.ipv4_addr = ipv4_addr.map(|ip| ip.to_string());
+            info.ipv6_addr = ipv6_addr.map(|ip| ip.to_string());
+        }
+
+        if config.include_subnet {
+            info.subnet = interface_subnet(interface);
+        }
+
+        if config.include_connection_type || config.include_is_wifi {
+            let connection_type = connection_type(interface);
+            if config.include_connection_type {
+                info.connection_type = Some(connection_type.to_string());
+            }
+            if config.include_is_wifi {
+                info.is_wifi = Some(connection_type == "wifi");
+            }
+        }
+
+        // Only wired interfaces report a speed here; Wi-Fi rates go to the Wi-Fi bucket
+        if config.include_link_speed {
+            info.link_speed_mbps = wired_link_speed_mbps(interface);
+        }
+    }
+
+    info
+}
+
+/// Check network connectivity by attempting to establish TCP connections to the given targets
+/// (by default, reliable DNS servers).
+/// Returns details of the first successful connection, or None if offline.
+fn check_network_connectivity(targets: &[String]) -> Option<ProbeSuccess> {
+    match PING_CONNECTOR.get() {
+        Some(connector) => {
+            let resolver = RESOLVER.get_or_init(|| Resolver::new(Duration::ZERO));
+            let timeout = CONNECT_TIMEOUT.get().copied().unwrap_or(PROBE_TIMEOUT);
+            probe_targets(connector, resolver, targets, timeout)
+        }
+        None => probe_tcp(targets),
+    }
+}
+
+/// Run the connectivity script, killing it once `timeout` has passed. Exit code 0
+/// counts as online, with the run time as latency. Also returns what the script
+/// printed, trimmed, unless that was nothing
+fn run_connectivity_script(
+    script: &str,
+    timeout: Duration,
+) -> (Option<ProbeSuccess>, Option<String>) {
+    let start = Instant::now();
+    let mut child = match Command::new(script)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => {
+            eprintln!("Failed to run connectivity script '{}': {}", script, e);
+            return (None, None);
+        }
+    };
+
+    // Read the output on another thread, so a script printing more than the pipe
+    // holds doesn't block before exiting
+    let reader = child.stdout.take().map(|mut stdout| {
+        thread::spawn(move || {
+            let mut output = String::new();
+            let _ = stdout.read_to_string(&mut output);
+            output
+        })
+    });
+
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break Some(status),
+            Ok(None) if start.elapsed() >= timeout => {
+                eprintln!(
+                    "Connectivity script '{}' didn't finish within {:?}, counting as offline",
+                    script, timeout
+                );
+                let _ = child.kill();
+                let _ = child.wait();
+                break None;
+            }
+            Ok(None) => sleep(Duration::from_millis(10)),
+            Err(e) => {
+                eprintln!("Failed to wait for connectivity script '{}': {}", script, e);
+                break None;
+            }
+        }
+    };
+    let latency = start.elapsed();
+
+    // After a timeout, a process the script started could still hold the pipe open
+    let output = match (status, reader) {
+        (Some(_), Some(reader)) => reader.join().ok(),
+        _ => None,
+    }
+    .map(|output| output.trim().to_string())
+    .filter(|output| !output.is_empty());
+
+    let probe = status
+        .filter(|status| status.success())
+        .map(|_| ProbeSuccess {
+            local_addr: None,
+            latency,
+        });
+    (probe, output)
+}
+
+/// Recent probe results of each connectivity target, oldest first
+#[derive(Debug, Default)]
+struct TargetHealth {
+    history: HashMap<String, VecDeque<bool>>,
+}
+
+impl TargetHealth {
+    /// Probe the targets most reliable first until one responds, recording the
+    /// result of each target that was tried
+    fn probe(&mut self, targets: &[String]) -> Option<ProbeSuccess> {
+        let mut ordered: Vec<&String> = targets.iter().collect();
+        // Stable, so equally reliable targets keep their configured order
+        ordered.sort_by(|a, b| self.reliability(b).total_cmp(&self.reliability(a)));
+
+        for target in ordered {
+            let result = check_network_connectivity(std::slice::from_ref(target));
+            let history = self.history.entry(target.clone()).or_default();
+            if history.len() == TARGET_HISTORY_LEN {
+                history.pop_front();
+            }
+            history.push_back(result.is_some());
+
+            if result.is_some() {
+                return result;
+            }
+        }
+        None
+    }
+
+    /// Fraction of a target's recent probes that succeeded. Targets that haven't
+    /// been tried yet count as reliable so they get tried
+    fn reliability(&self, target: &str) -> f64 {
+        match self.history.get(target) {
+            Some(history) if !history.is_empty() => {
+                history.iter().filter(|&&reachable| reachable).count() as f64 / history.len() as f64
+            }
+            _ => 1.0,
+        }
+    }
+
+    /// Send each tried target's reliability as a percentage gauge
+    fn report(&self, statsd: &Statsd) {
+        for target in self.history.keys() {
+            // StatsD uses ':' and '.' as separators in metric names
+            let name: String = target
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                .collect();
+            statsd.gauge(
+                &format!("target_reliability.{}", name),
+                self.reliability(target) * 100.0,
+            );
+        }
+    }
+}
+
+/// Probe every target, returning the first successful probe in target order and
+/// the fraction of targets that were reachable
+fn check_connectivity_quality(targets: &[String]) -> (Option<ProbeSuccess>, f64) {
+    let results: Vec<ProbeSuccess> = targets
+        .iter()
+        .filter_map(|target| check_network_connectivity(std::slice::from_ref(target)))
+        .collect();
+
+    let quality = results.len() as f64 / targets.len().max(1) as f64;
+    (results.first().copied(), quality)
+}
+
+/// Probe one of the connectivity targets on port 53 and one on port 443,
+/// describing which probe failed when only one of the two works
+fn check_degraded_connectivity(targets: &[String]) -> Option<String> {
+    let reachable = |port: u16| {
+        target_on_port(targets, port).is_some_and(|target| probe_tcp(&[target]).is_some())
+    };
+    let dns_reachable = reachable(53);
+    let https_reachable = reachable(443);
+
+    match (dns_reachable, https_reachable) {
+        (true, false) => Some("port 443 probes failed".to_string()),
+        (false, true) => Some("port 53 probes failed".to_string()),
+        _ => None,
+    }
+}
+
+/// The first target on `port`, or else the first target's host on `port`,
+/// keeping its timeout suffix
+fn target_on_port(targets: &[String], port: u16) -> Option<String> {
+    // Host, port and timeout suffix of a well-formed target
+    fn split(target: &str) -> Option<(&str, Option<u16>, &str)> {
+        let (host_port, _) = parse_target(target).ok()?;
+        let (host, port) = host_port.rsplit_once(':')?;
+        Some((host, port.parse().ok(), &target[host_port.len()..]))
+    }
+
+    if let Some(target) = targets
+        .iter()
+        .find(|target| split(target).is_some_and(|(_, target_port, _)| target_port == Some(port)))
+    {
+        return Some(target.clone());
+    }
+    let (host, _, timeout) = targets.iter().find_map(|target| split(target))?;
+    Some(format!("{}:{}{}", host, port, timeout))
+}
+
+/// Check IPv6 connectivity by attempting to establish TCP connections to IPv6 DNS servers.
+/// This is independent of the overall online/offline determination.
+fn check_ipv6_connectivity(port: u16) -> bool {
+    let targets: Vec<String> = DEFAULT_IPV6_PROBE_HOSTS
+        .iter()
+        .map(|host| format!("{}:{}", host, port))
+        .collect();
+
+    probe_tcp(&targets).is_some()
+}
+
+/// Probe targets over TCP using the shared resolver
+fn probe_tcp(targets: &[String]) -> Option<ProbeSuccess> {
+    let resolver = RESOLVER.get_or_init(|| Resolver::new(Duration::ZERO));
+    let timeout = CONNECT_TIMEOUT.get().copied().unwrap_or(PROBE_TIMEOUT);
+    probe_targets(&TcpConnector, resolver, targets, timeout)
+}
+
+/// Find the name of the network interface that owns the given local IP address
+fn interface_for_ip(ip: IpAddr) -> Option<String> {
+    get_if_addrs()
+        .ok()?
+        .into_iter()
+        .find(|interface| interface.ip() == ip)
+        .map(|interface| interface.name)
+}
+
+/// First IPv4 and IPv6 address of an interface, preferring global over link-local IPv6 addresses
+fn interface_addresses(interface: &str) -> (Option<IpAddr>, Option<IpAddr>) {
+    let addresses: Vec<IpAddr> = get_if_addrs()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|address| address.name == interface)
+        .map(|address| address.ip())
+        .collect();
+
+    let ipv4_addr = addresses.iter().copied().find(IpAddr::is_ipv4);
+    let is_link_local =
+        |ip: &IpAddr| matches!(ip, IpAddr::V6(ip) if ip.segments()[0] & 0xffc0 == 0xfe80);
+    let ipv6_addr = addresses
+        .iter()
+        .copied()
+        .filter(IpAddr::is_ipv6)
+        .min_by_key(is_link_local);
+
+    (ipv4_addr, ipv6_addr)
+}
+
+/// IPv4 subnet of an interface in CIDR form, from its first IPv4 address and netmask
+fn interface_subnet(interface: &str) -> Option<String> {
+    get_if_addrs()
+        .ok()?
+        .into_iter()
+        .filter(|address| address.name == interface)
+        .find_map(|address| match address.addr {
+            IfAddr::V4(v4) => {
+                let netmask = u32::from(v4.netmask);
+                let network = Ipv4Addr::from(u32::from(v4.ip) & netmask);
+                Some(format!("{}/{}", network, netmask.count_ones()))
+            }
+            IfAddr::V6(_) => None,
+        })
+}
+
+/// Get the name of the interface the OS uses for its default IPv4 route
+fn default_route_interface() -> Option<String> {
+    #[cfg(target_os = "macos")]
+    {
+        default_route_interface_macos()
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        default_route_interface_linux()
+    }
+    #[cfg(target_os = "windows")]
+    {
+        default_route_interface_windows()
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn default_route_interface_macos() -> Option<String> {
+    let output = Command::new("route")
+        .args(["-n", "get", "default"])
+        .output()
+        .ok()?;
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("interface:"))
+        .map(|interface| interface.trim().to_string())
+}
+
+#[cfg(target_os = "linux")]
+fn default_route_interface_linux() -> Option<String> {
+    let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+    parse_default_route_linux(&routes).map(|(interface, _)| interface)
+}
+
+/// Interface and gateway of the default route in /proc/net/route
+#[cfg(target_os = "linux")]
+fn parse_default_route_linux(routes: &str) -> Option<(String, Option<Ipv4Addr>)> {
+    // Columns: Iface Destination Gateway Flags RefCnt Use Metric Mask ...
+    // A default route has an all-zero destination and mask; prefer the lowest metric.
+    // The gateway is the address in host byte order, as hex
+    routes
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() >= 8 && fields[1] == "00000000" && fields[7] == "00000000" {
+                let metric = fields[6].parse::<u32>().unwrap_or(u32::MAX);
+                let gateway = u32::from_str_radix(fields[2], 16)
+                    .ok()
+                    .filter(|gateway| *gateway != 0)
+                    .map(|gateway| Ipv4Addr::from(gateway.to_ne_bytes()));
+                Some((metric, fields[0].to_string(), gateway))
+            } else {
+                None
+            }
+        })
+        .min_by_key(|(metric, _, _)| *metric)
+        .map(|(_, interface, gateway)| (interface, gateway))
+}
+
+#[cfg(target_os = "windows")]
+fn default_route_interface_windows() -> Option<String> {
+    let output = Command::new("powershell")
+        .args([
+            "-Command",
+            "Get-NetRoute -DestinationPrefix 0.0.0.0/0 | Sort-Object RouteMetric | Select-Object -First 1 -ExpandProperty InterfaceAlias",
+        ])
+        .output()
+        .ok()?;
+
+    let interface = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (!interface.is_empty()).then_some(interface)
+}
+
+/// IPv4 gateway of the default route, or `None` if there is none or it can't be determined
+fn default_gateway() -> Option<Ipv4Addr> {
+    #[cfg(target_os = "macos")]
+    {
+        default_gateway_macos()
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        default_gateway_linux()
+    }
+
+    #[cfg(target_os = "windows")]
+    {
+        default_gateway_windows()
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn default_gateway_macos() -> Option<Ipv4Addr> {
+    let output = Command::new("route")
+        .args(["-n", "get", "default"])
+        .output()
+        .ok()?;
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("gateway:"))
+        .and_then(|gateway| gateway.trim().parse().ok())
+}
+
+#[cfg(target_os = "linux")]
+fn default_gateway_linux() -> Option<Ipv4Addr> {
+    let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+    parse_default_route_linux(&routes).and_then(|(_, gateway)| gateway)
+}
+
+#[cfg(target_os = "windows")]
+fn default_gateway_windows() -> Option<Ipv4Addr> {
+    let output = Command::new("powershell")
+        .args([
+            "-Command",
+            "Get-NetRoute -DestinationPrefix 0.0.0.0/0 | Sort-Object RouteMetric | Select-Object -First 1 -ExpandProperty NextHop",
+        ])
+        .output()
+        .ok()?;
+
+    // On-link routes have a next hop of 0.0.0.0
+    String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse()
+        .ok()
+        .filter(|gateway: &Ipv4Addr| !gateway.is_unspecified())
+}
+
+/// Whether airplane mode is on, or `None` if it can't be determined
+fn airplane_mode() -> Option<bool> {
+    #[cfg(target_os = "macos")]
+    {
+        airplane_mode_macos()
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        airplane_mode_linux()
+    }
+
+    #[cfg(target_os = "windows")]
+    {
+        airplane_mode_windows()
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn airplane_mode_macos() -> Option<bool> {
+    // There's no airplane mode on macOS, so infer it from Wi-Fi and Bluetooth both being off
+    let ports = Command::new("networksetup")
+        .arg("-listallhardwareports")
+        .output()
+        .ok()?;
+    let ports = String::from_utf8_lossy(&ports.stdout).into_owned();
+    let wifi_device = ports
+        .lines()
+        .skip_while(|line| !line.contains("Wi-Fi") && !line.contains("AirPort"))
+        .find_map(|line| line.strip_prefix("Device:"))?
+        .trim()
+        .to_string();
+
+    let wifi_power = Command::new("networksetup")
+        .args(["-getairportpower", &wifi_device])
+        .output()
+        .ok()?;
+    let wifi_on = String::from_utf8_lossy(&wifi_power.stdout)
+        .trim()
+        .ends_with("On");
+
+    let bluetooth = Command::new("defaults")
+        .args([
+            "read",
+            "/Library/Preferences/com.apple.Bluetooth",
+            "ControllerPowerState",
+        ])
+        .output()
+        .ok()?;
+    let bluetooth_on = String::from_utf8_lossy(&bluetooth.stdout).trim() == "1";
+
+    Some(!wifi_on && !bluetooth_on)
+}
+
+#[cfg(target_os = "linux")]
+fn airplane_mode_linux() -> Option<bool> {
+    let output = Command::new("nmcli")
+        .args(["-t", "radio", "all"])
+        .output()
+        .ok()?;
+
+    // Fields: WIFI-HW:WIFI:WWAN-HW:WWAN, each "enabled" or "disabled"
+    let output = String::from_utf8_lossy(&output.stdout);
+    let fields: Vec<&str> = output.trim().split(':').collect();
+    if fields.len() < 4 {
+        return None;
+    }
+    Some(fields[1] != "enabled" && fields[3] != "enabled")
+}
+
+#[cfg(target_os = "windows")]
+fn airplane_mode_windows() -> Option<bool> {
+    // SystemRadioState is 1 while airplane mode is on
+    let output = Command::new("reg")
+        .args([
+            "query",
+            r"HKLM\SYSTEM\CurrentControlSet\Control\RadioManagement\SystemRadioState",
+        ])
+        .output()
+        .ok()?;
+
+    let value = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find(|line| line.contains("REG_DWORD"))?
+        .split_whitespace()
+        .last()?
+        .to_string();
+    Some(value == "0x1")
+}
+
+/// DNS servers the system is configured to use, in order and without
+/// duplicates, or `None` if they can't be determined
+fn dns_servers() -> Option<Vec<String>> {
+    #[cfg(target_os = "macos")]
+    let servers = dns_servers_macos();
+
+    #[cfg(target_os = "linux")]
+    let servers = dns_servers_linux();
+
+    #[cfg(target_os = "windows")]
+    let servers = dns_servers_windows();
+
+    let mut unique: Vec<String> = Vec::new();
+    for server in servers? {
+        if !unique.contains(&server) {
+            unique.push(server);
+        }
+    }
+    (!unique.is_empty()).then_some(unique)
+}
+
+#[cfg(target_os = "macos")]
+fn dns_servers_macos() -> Option<Vec<String>> {
+    let output = Command::new("scutil").arg("--dns").output().ok()?;
+
+    // Lines look like "  nameserver[0] : 192.168.1.1"
+    let servers = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| line.trim_start().starts_with("nameserver["))
+        .filter_map(|line| line.split_once(" : "))
+        .map(|(_, server)| server.trim().to_string())
+        .collect();
+    Some(servers)
+}
+
+#[cfg(target_os = "linux")]
+fn dns_servers_linux() -> Option<Vec<String>> {
+    // With systemd-resolved, resolv.conf only lists its local stub resolver
+    if let Ok(output) = Command::new("resolvectl").arg("dns").output()
+        && output.status.success()
+    {
+        // Lines look like "Link 2 (wlan0): 192.168.1.1 fe80::1%2"
+        let servers: Vec<String> = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| line.split_once(": "))
+            .flat_map(|(_, servers)| servers.split_whitespace())
+            .map(str::to_string)
+            .collect();
+        if !servers.is_empty() {
+            return Some(servers);
+        }
+    }
+
+    let resolv_conf = std::fs::read_to_string("/etc/resolv.conf").ok()?;
+    let servers = resolv_conf
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|server| server.split_whitespace().next())
+        .map(str::to_string)
+        .collect();
+    Some(servers)
+}
+
+#[cfg(target_os = "windows")]
+fn dns_servers_windows() -> Option<Vec<String>> {
+    let output = Command::new("powershell")
+        .args([
+            "-Command",
+            "Get-DnsClientServerAddress | Select-Object -ExpandProperty ServerAddresses",
+        ])
+        .output()
+        .ok()?;
+
+    let servers = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::trim)
+        .filter(|server| !server.is_empty())
+        .map(str::to_string)
+        .collect();
+    Some(servers)
+}
+
+/// Version of the operating system, e.g. "14.5" on macOS or "Ubuntu 24.04 LTS" on Linux
+fn os_version() -> Option<String> {
+    #[cfg(target_os = "macos")]
+    let version = os_version_macos();
+
+    #[cfg(target_os = "linux")]
+    let version = os_version_linux();
+
+    #[cfg(target_os = "windows")]
+    let version = os_version_windows();
+
+    version.filter(|version| !version.is_empty())
+}
+
+#[cfg(target_os = "macos")]
+fn os_version_macos() -> Option<String> {
+    let output = Command::new("sw_vers")
+        .arg("-productVersion")
+        .output()
+        .ok()?;
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+#[cfg(target_os = "linux")]
+fn os_version_linux() -> Option<String> {
+    // Lines look like PRETTY_NAME="Ubuntu 24.04 LTS"
+    let os_release = std::fs::read_to_string("/etc/os-release").ok()?;
+    os_release
+        .lines()
+        .find_map(|line| line.strip_prefix("PRETTY_NAME="))
+        .map(|name| name.trim_matches('"').to_string())
+}
+
+#[cfg(target_os = "windows")]
+fn os_version_windows() -> Option<String> {
+    let output = Command::new("powershell")
+        .args([
+            "-Command",
+            "(Get-CimInstance Win32_OperatingSystem).Version",
+        ])
+        .output()
+        .ok()?;
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Whether the machine runs on AC power rather than battery. Machines without
+/// a battery, and those whose power source can't be read, count as on AC power
+fn on_ac_power() -> bool {
+    #[cfg(target_os = "macos")]
+    let on_battery = on_battery_macos();
+
+    #[cfg(target_os = "linux")]
+    let on_battery = on_battery_linux();
+
+    #[cfg(target_os = "windows")]
+    let on_battery = on_battery_windows();
+
+    !on_battery
+}
+
+#[cfg(target_os = "macos")]
+fn on_battery_macos() -> bool {
+    // The first line reads "Now drawing from 'Battery Power'" or "... 'AC Power'"
+    Command::new("pmset")
+        .args(["-g", "batt"])
+        .output()
+        .is_ok_and(|output| String::from_utf8_lossy(&output.stdout).contains("'Battery Power'"))
+}
+
+#[cfg(target_os = "linux")]
+fn on_battery_linux() -> bool {
+    let Ok(supplies) = read_dir("/sys/class/power_supply") else {
+        return false;
+    };
+    let supplies: Vec<PathBuf> = supplies.flatten().map(|entry| entry.path()).collect();
+    let read = |path: &Path, file: &str| {
+        std::fs::read_to_string(path.join(file))
+            .map_or(String::new(), |value| value.trim().to_string())
+    };
+
+    // On battery when there is a battery and no mains supply (the AC adapter) is online
+    supplies
+        .iter()
+        .any(|supply| read(supply, "type") == "Battery")
+        && !supplies
+            .iter()
+            .any(|supply| read(supply, "type") == "Mains" && read(supply, "online") == "1")
+}
+
+#[cfg(target_os = "windows")]
+fn on_battery_windows() -> bool {
+    // BatteryStatus 1 means discharging; there is no Win32_Battery without a battery
+    Command::new("powershell")
+        .args(["-Command", "(Get-CimInstance Win32_Battery).BatteryStatus"])
+        .output()
+        .is_ok_and(|output| String::from_utf8_lossy(&output.stdout).trim() == "1")
+}
+
+/// Proxy the system is configured to use: a proxy URL, or a PAC file URL on macOS
+fn system_proxy() -> Option<String> {
+    #[cfg(target_os = "macos")]
+    if let Some(proxy) = system_proxy_macos() {
+        return Some(proxy);
+    }
+
+    #[cfg(target_os = "windows")]
+    if let Some(proxy) = system_proxy_windows() {
+        return Some(proxy);
+    }
+
+    [
+        "HTTPS_PROXY",
+        "https_proxy",
+        "HTTP_PROXY",
+        "http_proxy",
+        "ALL_PROXY",
+        "all_proxy",
+    ]
+    .iter()
+    .find_map(|name| env::var(name).ok().filter(|proxy| !proxy.is_empty()))
+}
+
+#[cfg(target_os = "macos")]
+fn system_proxy_macos() -> Option<String> {
+    let output = Command::new("scutil").arg("--proxy").output().ok()?;
 
-    let client = AwClient::new("localhost", 5600, "aw-watcher-network").unwrap();
+    // Lines look like "  HTTPSProxy : proxy.example.com"
+    let output = String::from_utf8_lossy(&output.stdout);
+    let settings: HashMap<&str, &str> = output
+        .lines()
+        .filter_map(|line| line.split_once(" : "))
+        .map(|(key, value)| (key.trim(), value.trim()))
+        .collect();
+    let enabled = |key: &str| settings.get(key) == Some(&"1");
 
-    // Create or get buckets
-    client
-        .create_bucket_simple(&bucket_id, event_type)
-        .expect("Failed to create network bucket");
+    if enabled("ProxyAutoConfigEnable") {
+        return settings
+            .get("ProxyAutoConfigURLString")
+            .map(|url| url.to_string());
+    }
+    [("HTTPS", "http"), ("HTTP", "http"), ("SOCKS", "socks5")]
+        .iter()
+        .filter(|(prefix, _)| enabled(&format!("{}Enable", prefix)))
+        .find_map(|(prefix, scheme)| {
+            let host = settings.get(format!("{}Proxy", prefix).as_str())?;
+            let port = settings.get(format!("{}Port", prefix).as_str())?;
+            Some(format!("{}://{}:{}", scheme, host, port))
+        })
+}
 
-    client
-        .create_bucket_simple(&wifi_bucket_id, wifi_event_type)
-        .expect("Failed to create Wi-Fi bucket");
+#[cfg(target_os = "windows")]
+fn system_proxy_windows() -> Option<String> {
+    let output = Command::new("powershell")
+        .args(["-Command", "netsh winhttp show proxy"])
+        .output()
+        .ok()?;
 
-    // Start Wi-Fi SSID scanning thread on supported platforms
+    // "    Proxy Server(s) :  proxy.example.com:8080", missing for direct access
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("Proxy Server(s)"))
+        .and_then(|rest| rest.split_once(':'))
+        .map(|(_, proxy)| proxy.trim().to_string())
+        .filter(|proxy| !proxy.is_empty())
+}
+
+/// Classify an interface as "cellular", "wifi", "vpn" or "wired"
+fn connection_type(interface: &str) -> &'static str {
+    #[cfg(target_os = "macos")]
     {
-        let wifi_scan_interval = config.wifi_scan_interval;
-        // Create a new client instance for the WiFi thread since AwClient doesn't implement Clone
-        let wifi_client = AwClient::new("localhost", 5600, "aw-watcher-network").unwrap();
-        let wifi_bucket = wifi_bucket_id.clone();
+        connection_type_macos(interface)
+    }
 
-        let current_ssids: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
-        // Uncomment this line if you need to access SSIDs from the main thread
-        // let ssids_for_main = Arc::clone(&current_ssids);
+    #[cfg(target_os = "linux")]
+    {
+        connection_type_linux(interface)
+    }
 
-        thread::spawn(move || {
-            wifi_ssid_watcher(wifi_scan_interval, wifi_client, wifi_bucket, current_ssids);
-        });
+    #[cfg(target_os = "windows")]
+    {
+        connection_type_windows(interface)
     }
+}
 
-    // Main loop to check network status periodically
-    loop {
-        // Record the start time of this iteration
-        let loop_start = Instant::now();
+#[cfg(target_os = "macos")]
+fn connection_type_macos(interface: &str) -> &'static str {
+    if ["utun", "ipsec", "ppp"]
+        .iter()
+        .any(|prefix| interface.starts_with(prefix))
+    {
+        return "vpn";
+    }
 
-        let status = check_network_connectivity();
-        // Create and send event
-        let mut data_map = Map::new();
-        data_map.insert(
-            "title".to_string(),
-            Value::String(if status {
-                // the extra space serves a purpose in the coloring in the timeline view.
-                "online ".to_string()
-            } else {
-                "offline".to_string()
-            }),
-        );
+    // Map the device to its hardware port, e.g. "Hardware Port: Wi-Fi" followed by "Device: en0"
+    let hardware_port = Command::new("networksetup")
+        .arg("-listallhardwareports")
+        .output()
+        .ok()
+        .and_then(|output| {
+            let output = String::from_utf8_lossy(&output.stdout).into_owned();
+            let mut port = None;
+            for line in output.lines() {
+                if let Some(name) = line.strip_prefix("Hardware Port:") {
+                    port = Some(name.trim().to_string());
+                } else if line.strip_prefix("Device:").map(str::trim) == Some(interface) {
+                    return port;
+                }
+            }
+            None
+        })
+        .unwrap_or_default();
 
-        let event = Event {
-            id: None,
-            timestamp: Utc::now(),
-            duration: TimeDelta::seconds(polling_interval as i64),
-            data: data_map,
-        };
+    if ["Cellular", "WWAN", "iPhone"]
+        .iter()
+        .any(|name| hardware_port.contains(name))
+    {
+        "cellular"
+    } else if hardware_port.contains("Wi-Fi") || hardware_port.contains("AirPort") {
+        "wifi"
+    } else {
+        "wired"
+    }
+}
 
-        match client.heartbeat(&bucket_id, &event, polling_interval as f64) {
-            Ok(_) => (),
-            Err(e) => eprintln!("Error sending heartbeat: {}", e),
-        }
+#[cfg(target_os = "linux")]
+fn connection_type_linux(interface: &str) -> &'static str {
+    if ["ww", "rmnet"]
+        .iter()
+        .any(|prefix| interface.starts_with(prefix))
+    {
+        "cellular"
+    } else if interface.starts_with("wl")
+        || Path::new(&format!("/sys/class/net/{}/wireless", interface)).exists()
+    {
+        "wifi"
+    } else if ["tun", "tap", "wg", "ppp", "tailscale"]
+        .iter()
+        .any(|prefix| interface.starts_with(prefix))
+    {
+        "vpn"
+    } else {
+        "wired"
+    }
+}
 
-        // Calculate how much time has elapsed in this iteration
-        let elapsed = loop_start.elapsed();
+#[cfg(target_os = "windows")]
+fn connection_type_windows(interface: &str) -> &'static str {
+    // Interfaces are identified by their alias, e.g. "Cellular" or "Wi-Fi 2"
+    let alias = interface.to_lowercase();
 
-        // Calculate the time to sleep to maintain consistent intervals
-        if elapsed < Duration::from_secs(polling_interval) {
-            let sleep_time = Duration::from_secs(polling_interval) - elapsed;
-            sleep(sleep_time);
-        } else {
-            // If operations took longer than polling_interval, don't sleep
-            // but log a warning about the missed interval
-            eprintln!(
-                "Warning: Operations took longer than polling interval ({:?} > {}s)",
-                elapsed, polling_interval
-            );
-        }
+    if ["cellular", "mobile broadband", "wwan"]
+        .iter()
+        .any(|name| alias.contains(name))
+    {
+        "cellular"
+    } else if ["wi-fi", "wireless", "wlan"]
+        .iter()
+        .any(|name| alias.contains(name))
+    {
+        "wifi"
+    } else if ["vpn", "tap", "wireguard"]
+        .iter()
+        .any(|name| alias.contains(name))
+    {
+        "vpn"
+    } else {
+        "wired"
     }
 }
 
-/// Check network connectivity by attempting to establish TCP connections to reliable DNS servers
-fn check_network_connectivity() -> bool {
-    // List of reliable DNS servers to check connectivity against
-    let targets = [
-        "1.1.1.1:53", // Cloudflare DNS
-        "8.8.8.8:53", // Google DNS
-        "9.9.9.9:53", // Quad9 DNS
-    ];
+/// What to install when none of the platform's Wi-Fi scanning tools is found,
+/// or `None` if one is
+fn missing_wifi_scan_tools() -> Option<&'static str> {
+    #[cfg(target_os = "macos")]
+    let (tools, install_hint) = (
+        ["system_profiler"].as_slice(),
+        "system_profiler ships with macOS in /usr/sbin; make sure that is in PATH",
+    );
 
-    for target in targets {
-        // Parse the address and attempt to establish a connection
-        if let Ok(mut addrs) = target.to_socket_addrs() {
-            if let Some(addr) = addrs.next() {
-                if TcpStream::connect_timeout(&addr, Duration::from_secs(1)).is_ok() {
-                    return true;
-                }
-            }
-        }
+    #[cfg(target_os = "linux")]
+    let (tools, install_hint) = (
+        LINUX_WIFI_BACKENDS.as_slice(),
+        "Install NetworkManager (nmcli), wireless-tools (iwlist) or iw",
+    );
+
+    #[cfg(target_os = "windows")]
+    let (tools, install_hint) = (
+        ["netsh"].as_slice(),
+        "netsh ships with Windows in C:\\Windows\\System32; make sure that is in PATH",
+    );
+
+    (!tools.iter().any(|tool| command_exists(tool))).then_some(install_hint)
+}
+
+/// Whether the system has a wireless interface, or `None` if it can't be determined
+fn has_wireless_interface() -> Option<bool> {
+    #[cfg(target_os = "macos")]
+    {
+        let output = Command::new("networksetup")
+            .arg("-listallhardwareports")
+            .output()
+            .ok()?;
+        let output = String::from_utf8_lossy(&output.stdout);
+        Some(output.contains("Hardware Port: Wi-Fi") || output.contains("Hardware Port: AirPort"))
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        // Look at every interface, including ones without an address that if-addrs doesn't list
+        let interfaces = read_dir("/sys/class/net").ok()?;
+        Some(interfaces.flatten().any(|entry| {
+            entry.path().join("wireless").exists()
+                || entry.file_name().to_string_lossy().starts_with("wl")
+        }))
+    }
+
+    #[cfg(target_os = "windows")]
+    {
+        let output = Command::new("netsh")
+            .args(["wlan", "show", "interfaces"])
+            .output()
+            .ok()?;
+        // Each wireless interface is listed with a "Name : ..." line
+        Some(
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .any(|line| line.trim().starts_with("Name") && line.contains(':')),
+        )
     }
-    false
 }
 
 /// Function to watch for Wi-Fi SSIDs in a separate thread
 fn wifi_ssid_watcher(
-    scan_interval: u64,
-    client: AwClient,
+    config: AppConfig,
+    sinks: Arc<dyn EventSink>,
     bucket_id: String,
-    ssids: Arc<Mutex<Vec<String>>>,
+    event_type: &str,
+    transitions_bucket_id: Option<String>,
+    wifi_state: Arc<Mutex<WifiState>>,
+    diagnostics: Option<Arc<Diagnostics>>,
 ) {
+    // Already validated at startup
+    let active_hours = config.active_hours().ok().flatten();
+    let statsd = Statsd::from_config(&config);
+    // Scans that took longer than the scan interval
+    let mut overrun_total: u64 = 0;
+    // Whether the last successful scan found no networks at all
+    let mut last_scan_empty = false;
+    // Connected network found by the last successful scan, once there has been one
+    let mut previous_ssid: Option<Option<String>> = None;
+    // Changes of network before this point are not sent as transitions
+    let warmup_end = Instant::now() + Duration::from_secs(config.transition_warmup_seconds);
+    // Last heartbeat sent and its bucket, for the final heartbeat at shutdown
+    let mut last_sent: Option<(String, Event, u64)> = None;
+    let mut last_heartbeat: Option<Instant> = None;
+
     loop {
+        if shutdown_due() {
+            break;
+        }
+
+        if wait_for_active_hours(active_hours, "Wi-Fi") {
+            continue;
+        }
+
         // Record the start time of this iteration
         let loop_start = Instant::now();
 
+        let scan_interval = match config.wifi_scan_interval_docked {
+            Some(docked_interval) if on_ac_power() => docked_interval,
+            _ => config.wifi_scan_interval,
+        };
+
         // Get current Wi-Fi SSIDs
-        match get_wifi_ssids() {
-            Ok((connected_ssid, detected_ssids)) => {
-                // Update the shared SSID list
-                let mut ssids_guard = ssids.lock().unwrap();
-                *ssids_guard = detected_ssids.clone();
-                drop(ssids_guard); // Release the lock
-
-                // Create event data
-                let mut data_map = Map::new();
-
-                // Add SSIDs as an array
-                let ssids_json: Vec<Value> = detected_ssids
-                    .iter()
-                    .map(|ssid| Value::String(ssid.clone()))
-                    .collect();
+        let scan_start = Instant::now();
+        let scan_result = get_wifi_ssids(&config);
+        let scan_duration = scan_start.elapsed();
+        let keepalive_due = config.keepalive_interval_seconds > 0
+            && last_heartbeat.is_some_and(|sent: Instant| {
+                sent.elapsed() >= interval_duration(config.keepalive_interval_seconds)
+            });
 
-                data_map.insert("ssids".to_string(), Value::Array(ssids_json));
+        match scan_result {
+            // The empty state was already sent; stay quiet until networks show up again
+            Ok(WifiScan {
+                connected_ssid: None,
+                ref ssids,
+                ..
+            }) if config.suppress_repeated_empty_wifi
+                && last_scan_empty
+                && ssids.is_empty()
+                && !keepalive_due => {}
+            Ok(WifiScan {
+                connected_ssid,
+                ssids: detected_ssids,
+                link_speed_mbps,
+                phy_mode,
+                scan_tool,
+                details,
+            }) => {
+                if let Some(statsd) = &statsd {
+                    statsd.gauge("visible_networks", detected_ssids.len() as f64);
+                }
+                last_scan_empty = connected_ssid.is_none() && detected_ssids.is_empty();
 
-                // No need to add connected_ssid as a separate field since it's already in the title
+                // Update the shared Wi-Fi state
+                let mut state_guard = wifi_state.lock().unwrap();
+                state_guard.connected_ssid = connected_ssid.clone();
+                state_guard.ssids = detected_ssids.clone();
+                drop(state_guard); // Release the lock
 
-                // Set title to connected network or "Not connected"
-                let title = match connected_ssid {
-                    Some(ssid) => ssid,
-                    None => {
-                        if detected_ssids.is_empty() {
-                            "No Wi-Fi networks".to_string()
-                        } else {
-                            "Not connected".to_string()
-                        }
+                if let Some(transitions_bucket_id) = &transitions_bucket_id {
+                    if loop_start >= warmup_end
+                        && let Some(from_ssid) = &previous_ssid
+                        && *from_ssid != connected_ssid
+                    {
+                        send_wifi_transition(
+                            &config,
+                            sinks.as_ref(),
+                            transitions_bucket_id,
+                            from_ssid.as_deref(),
+                            connected_ssid.as_deref(),
+                        );
                     }
-                };
+                    previous_ssid = Some(connected_ssid.clone());
+                }
+
+                // Fields about the scan as a whole, starting with how long it took
+                let mut scan_data = Map::new();
+                scan_data.insert(
+                    "scan_duration_ms".to_string(),
+                    Value::from(scan_duration.as_millis() as u64),
+                );
+
+                if config.include_timing_stats {
+                    scan_data.insert("overrun_total".to_string(), Value::from(overrun_total));
+                }
+
+                if config.include_scan_tool {
+                    scan_data.insert("scan_tool".to_string(), Value::String(scan_tool));
+                }
+
+                // Fields about the connected network
+                let mut connected_data = Map::new();
+
+                if config.include_link_speed
+                    && let Some(link_speed) = link_speed_mbps
+                {
+                    connected_data.insert("link_speed_mbps".to_string(), Value::from(link_speed));
+                }
+
+                if config.include_phy_mode
+                    && let Some(phy_mode) = phy_mode
+                {
+                    connected_data.insert("phy_mode".to_string(), Value::String(phy_mode));
+                }
+
+                let data_maps = if config.wifi_event_granularity == WIFI_GRANULARITY_PER_NETWORK {
+                    per_network_wifi_data(
+                        &config,
+                        &detected_ssids,
+                        connected_ssid.as_deref(),
+                        &details,
+                        &scan_data,
+                        &connected_data,
+                    )
+                } else {
+                    // Create event data
+                    let mut data_map = scan_data;
+                    data_map.extend(connected_data);
+
+                    // Add SSIDs as an array
+                    let ssids_json: Vec<Value> = detected_ssids
+                        .iter()
+                        .map(|ssid| Value::String(ssid.clone()))
+                        .collect();
+
+                    data_map.insert("ssids".to_string(), Value::Array(ssids_json));
 
-                data_map.insert("title".to_string(), Value::String(title));
+                    // No need to add connected_ssid as a separate field since it's already in the title
 
-                // Create and send event
-                let event = Event {
-                    id: None,
-                    timestamp: Utc::now(),
-                    duration: TimeDelta::seconds(scan_interval as i64),
-                    data: data_map,
+                    // Set title to connected network (or its label) or "Not connected"
+                    let title = match connected_ssid {
+                        Some(ssid) => ssid_title(&config, &ssid, &mut data_map),
+                        None => {
+                            if detected_ssids.is_empty() {
+                                "No Wi-Fi networks".to_string()
+                            } else {
+                                "Not connected".to_string()
+                            }
+                        }
+                    };
+
+                    data_map.insert("title".to_string(), Value::String(title));
+                    vec![data_map]
+                };
+
+                let target_bucket = config.bucket_id_for_today(&bucket_id);
+                let mut result = if target_bucket != bucket_id && config.create_buckets {
+                    sinks.ensure_bucket(&target_bucket, event_type)
+                } else {
+                    Ok(())
                 };
 
-                match client.heartbeat(&bucket_id, &event, scan_interval as f64) {
+                // Create and send events. Every scan carries its own scan_duration_ms,
+                // so heartbeats never merge and the pulsetime only spans one scan
+                let pulsetime = scan_interval;
+                let duration = event_duration(&config, scan_interval, last_heartbeat, loop_start);
+                for data_map in data_maps {
+                    let mut event = Event {
+                        id: None,
+                        timestamp: Utc::now(),
+                        duration,
+                        data: data_map,
+                    };
+
+                    if config.embed_timestamp {
+                        embed_timestamp(&mut event);
+                    }
+                    add_host_info(&mut event.data);
+                    event.data = config.add_static_tags(config.rename_fields(event.data));
+
+                    result =
+                        result.and_then(|_| sinks.send(&target_bucket, &event, pulsetime as f64));
+                    last_sent = Some((target_bucket.clone(), event, pulsetime));
+                }
+                last_heartbeat = Some(loop_start);
+
+                match result {
                     Ok(_) => (),
-                    Err(e) => eprintln!("Error sending Wi-Fi heartbeat: {}", e),
+                    Err(e) => {
+                        eprintln!("Error sending Wi-Fi heartbeat: {}", e);
+                        if let Some(statsd) = &statsd {
+                            statsd.count("heartbeat_failures", 1);
+                        }
+                        if let Some(diagnostics) = &diagnostics {
+                            diagnostics.report(
+                                "error",
+                                "wifi",
+                                &format!("Heartbeat failed: {}", e),
+                            );
+                        }
+                    }
                 }
             }
             Err(e) => {
                 eprintln!("Error scanning Wi-Fi networks: {}", e);
+                if let Some(diagnostics) = &diagnostics {
+                    diagnostics.report("error", "wifi-scan", &e);
+                }
             }
         }
 
@@ -283,38 +5343,357 @@ fn wifi_ssid_watcher(
         let elapsed = loop_start.elapsed();
 
         // Calculate the time to sleep to maintain consistent intervals
-        if elapsed < Duration::from_secs(scan_interval) {
-            let sleep_time = Duration::from_secs(scan_interval) - elapsed;
-            sleep(sleep_time);
-        } else {
-            // If operations took longer than scan_interval, don't sleep
+        let overran = elapsed >= interval_duration(scan_interval);
+        if overran {
+            // Operations took longer than scan_interval
             eprintln!(
                 "Warning: Wi-Fi scan operations took longer than polling interval ({:?} > {}s)",
                 elapsed, scan_interval
             );
+            overrun_total += 1;
+            if let Some(statsd) = &statsd {
+                statsd.count("overrun_total.wifi", 1);
+            }
+        }
+        if config.align_to_clock {
+            sleep_until_deadline(until_clock_boundary(interval_duration(scan_interval)));
+        } else if !overran {
+            sleep_until_deadline(interval_duration(scan_interval) - elapsed);
         }
     }
+
+    send_final_event(sinks.as_ref(), last_sent, false);
+}
+
+/// Title of a Wi-Fi event for an SSID: its label if it has one, keeping the
+/// SSID in "raw_ssid", otherwise the SSID itself
+fn ssid_title(config: &AppConfig, ssid: &str, data: &mut Map<String, Value>) -> String {
+    match config.ssid_label(ssid) {
+        Some(label) => {
+            data.insert("raw_ssid".to_string(), Value::String(ssid.to_string()));
+            label.to_string()
+        }
+        None => ssid.to_string(),
+    }
+}
+
+/// Data of the Wi-Fi events for wifi_event_granularity = "per_network": one per
+/// visible network, marking the connected one
+fn per_network_wifi_data(
+    config: &AppConfig,
+    ssids: &[String],
+    connected_ssid: Option<&str>,
+    details: &HashMap<String, NetworkDetails>,
+    scan_data: &Map<String, Value>,
+    connected_data: &Map<String, Value>,
+) -> Vec<Map<String, Value>> {
+    ssids
+        .iter()
+        .map(|ssid| {
+            let mut data_map = scan_data.clone();
+            let connected = connected_ssid == Some(ssid.as_str());
+            if connected {
+                data_map.extend(connected_data.clone());
+            }
+            data_map.insert("ssid".to_string(), Value::String(ssid.clone()));
+            data_map.insert("connected".to_string(), Value::Bool(connected));
+
+            if let Some(details) = details.get(ssid) {
+                if let Some(signal) = details.signal {
+                    data_map.insert("signal".to_string(), Value::from(signal));
+                }
+                if let Some(channel) = details.channel {
+                    data_map.insert("channel".to_string(), Value::from(channel));
+                }
+                if let Some(security) = &details.security {
+                    data_map.insert("security".to_string(), Value::String(security.clone()));
+                }
+            }
+
+            let title = ssid_title(config, ssid, &mut data_map);
+            data_map.insert("title".to_string(), Value::String(title));
+            data_map
+        })
+        .collect()
+}
+
+/// Record a change of the connected Wi-Fi network, `None` being not connected
+fn send_wifi_transition(
+    config: &AppConfig,
+    sinks: &dyn EventSink,
+    bucket_id: &str,
+    from_ssid: Option<&str>,
+    to_ssid: Option<&str>,
+) {
+    let mut data_map = Map::new();
+    data_map.insert(
+        "title".to_string(),
+        Value::String(format!(
+            "{} -> {}",
+            from_ssid.unwrap_or("Not connected"),
+            to_ssid.unwrap_or("Not connected")
+        )),
+    );
+    data_map.insert("from_ssid".to_string(), Value::from(from_ssid));
+    data_map.insert("to_ssid".to_string(), Value::from(to_ssid));
+    add_session_id(&mut data_map);
+
+    let event = Event {
+        id: None,
+        timestamp: Utc::now(),
+        duration: TimeDelta::zero(),
+        data: config.add_static_tags(data_map),
+    };
+
+    if let Err(e) = sinks.insert_event(bucket_id, &event) {
+        eprintln!("Error sending Wi-Fi transition: {}", e);
+    }
+}
+
+/// Record a change of the system proxy, `None` being no proxy
+fn send_proxy_change(
+    config: &AppConfig,
+    sinks: &dyn EventSink,
+    bucket_id: &str,
+    from_proxy: Option<&str>,
+    to_proxy: Option<&str>,
+) {
+    let mut data_map = Map::new();
+    data_map.insert(
+        "title".to_string(),
+        Value::String(format!(
+            "{} -> {}",
+            from_proxy.unwrap_or("No proxy"),
+            to_proxy.unwrap_or("No proxy")
+        )),
+    );
+    data_map.insert("from_proxy".to_string(), Value::from(from_proxy));
+    data_map.insert("to_proxy".to_string(), Value::from(to_proxy));
+    add_session_id(&mut data_map);
+
+    let event = Event {
+        id: None,
+        timestamp: Utc::now(),
+        duration: TimeDelta::zero(),
+        data: config.add_static_tags(data_map),
+    };
+
+    if let Err(e) = sinks.insert_event(bucket_id, &event) {
+        eprintln!("Error sending proxy change: {}", e);
+    }
+}
+
+/// Result of a Wi-Fi scan
+#[derive(Debug, Default)]
+struct WifiScan {
+    /// SSID of the currently connected network, if any
+    connected_ssid: Option<String>,
+    /// All SSIDs seen, sorted and deduplicated
+    ssids: Vec<String>,
+    /// Negotiated link rate of the connected network in Mbps, if known
+    link_speed_mbps: Option<f64>,
+    /// Wi-Fi standard of the connected network, e.g. "802.11ax", if known
+    phy_mode: Option<String>,
+    /// Tool the scan was done with, e.g. "nmcli"
+    scan_tool: String,
+    /// Signal, channel and security of visible networks by SSID, where known
+    details: HashMap<String, NetworkDetails>,
 }
 
-/// Get available Wi-Fi SSIDs using platform-specific commands
-fn get_wifi_ssids() -> Result<(Option<String>, Vec<String>), String> {
+/// What a scan reports about a visible network besides its SSID
+#[derive(Debug, Default, Clone)]
+struct NetworkDetails {
+    /// Signal strength: dBm on macOS, percent on Linux and Windows
+    signal: Option<i64>,
+    /// Channel number
+    channel: Option<u64>,
+    /// Security, e.g. "WPA2 Personal", as the platform names it
+    security: Option<String>,
+}
+
+/// Get available Wi-Fi SSIDs using platform-specific commands.
+/// If `scan_command` is set, it replaces the built-in scanning command and its
+/// output is fed to the platform parser.
+fn get_wifi_ssids(config: &AppConfig) -> Result<WifiScan, String> {
     #[cfg(target_os = "macos")]
+    let scan = get_wifi_ssids_macos(config.scan_command());
+
+    #[cfg(target_os = "linux")]
+    let scan = get_wifi_ssids_linux(
+        config.scan_command(),
+        &config.linux_wifi_backends,
+        config.require_unprivileged_scan,
+    );
+
+    #[cfg(target_os = "windows")]
+    let scan = get_wifi_ssids_windows(config.scan_command());
+
+    // The parsers list hidden networks as HIDDEN_SSID
+    let mut scan = scan?;
+    if !config.report_hidden_ssids {
+        scan.ssids.retain(|ssid| ssid != HIDDEN_SSID);
+    }
+
+    if config.wifi_event_granularity == WIFI_GRANULARITY_PER_NETWORK && scan.details.is_empty() {
+        scan.details = scan_network_details();
+    }
+
+    // Leave untracked networks out, and don't name the connected one if it is untracked
+    scan.ssids.retain(|ssid| config.ssid_tracked(ssid));
+    if let Some(ssid) = &scan.connected_ssid
+        && !config.ssid_tracked(ssid)
     {
-        get_wifi_ssids_macos()
+        scan.connected_ssid = Some(UNTRACKED_SSID.to_string());
+    }
+    Ok(scan)
+}
+
+/// Signal, channel and security of visible networks, from a second scan on
+/// Linux and Windows whose default scan output doesn't include them. On macOS
+/// they come with the scan itself
+fn scan_network_details() -> HashMap<String, NetworkDetails> {
+    #[cfg(target_os = "macos")]
+    let details = HashMap::new();
+
+    #[cfg(target_os = "linux")]
+    let details = run_linux_backend(
+        "nmcli",
+        &[
+            "-t",
+            "-f",
+            "SSID,SIGNAL,CHAN,SECURITY",
+            "device",
+            "wifi",
+            "list",
+        ],
+    )
+    .map(|output| parse_network_details_linux(&output))
+    .unwrap_or_default();
+
+    #[cfg(target_os = "windows")]
+    let details = Command::new("powershell")
+        .args(["-Command", "netsh wlan show networks mode=bssid"])
+        .output()
+        .map(|output| parse_network_details_windows(&String::from_utf8_lossy(&output.stdout)))
+        .unwrap_or_default();
+
+    details
+}
+
+/// Parse the number a value starts with, e.g. -55 from "-55 dBm / -90 dBm"
+/// or 36 from "36 (5GHz, 80MHz)"
+fn parse_leading_number<T: std::str::FromStr>(value: &str) -> Option<T> {
+    value
+        .trim()
+        .split(|c: char| !c.is_ascii_digit() && c != '-')
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Name to list a scanned network under: the SSID, or HIDDEN_SSID when it is empty
+/// or only NUL bytes, which iw and iwlist print as "\x00"
+#[cfg(any(target_os = "linux", target_os = "windows"))]
+fn scanned_ssid(ssid: &str) -> String {
+    if ssid.replace("\\x00", "").is_empty() {
+        HIDDEN_SSID.to_string()
+    } else {
+        ssid.to_string()
+    }
+}
+
+/// Warn at startup if a configured scan command is unlikely to work
+fn validate_scan_command(command: &str) {
+    match command.split_whitespace().next() {
+        Some(program) if !command_exists(program) => eprintln!(
+            "Warning: Wi-Fi scan command '{}' was not found; scans will fail until it is installed",
+            program
+        ),
+        Some(_) => println!("Using custom Wi-Fi scan command: {}", command),
+        None => eprintln!("Warning: Wi-Fi scan command is empty"),
+    }
+}
+
+/// Check whether a program exists, either as a path or somewhere on PATH
+fn command_exists(program: &str) -> bool {
+    let path = Path::new(program);
+    if path.components().count() > 1 {
+        return path.is_file();
+    }
+
+    env::var_os("PATH").is_some_and(|paths| {
+        env::split_paths(&paths).any(|dir| {
+            let candidate = dir.join(program);
+            candidate.is_file() || (cfg!(windows) && candidate.with_extension("exe").is_file())
+        })
+    })
+}
+
+/// Parse a link rate such as "866", "130 Mbit/s" or "1.3 Gbps" into Mbps
+fn parse_rate_mbps(rate: &str) -> Option<f64> {
+    let rate = rate.trim();
+    let number_end = rate
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(rate.len());
+    let value: f64 = rate[..number_end].parse().ok()?;
+    let unit = rate[number_end..].trim().to_ascii_lowercase();
+
+    if unit.starts_with('g') {
+        Some(value * 1000.0)
+    } else if unit.starts_with('k') {
+        Some(value / 1000.0)
+    } else {
+        // Plain numbers are already in Mbps
+        Some(value)
     }
+}
 
+/// Read the speed of a wired interface in Mbps (Linux only)
+fn wired_link_speed_mbps(interface: &str) -> Option<f64> {
     #[cfg(target_os = "linux")]
     {
-        get_wifi_ssids_linux()
+        // Wireless and virtual interfaces fail to read or report -1
+        let speed = std::fs::read_to_string(format!("/sys/class/net/{}/speed", interface)).ok()?;
+        speed
+            .trim()
+            .parse::<i64>()
+            .ok()
+            .filter(|speed| *speed > 0)
+            .map(|speed| speed as f64)
     }
-    #[cfg(target_os = "windows")]
+
+    #[cfg(not(target_os = "linux"))]
     {
-        get_wifi_ssids_windows()
+        let _ = interface;
+        None
     }
 }
 
+/// Program a custom scan command runs, reported as its scan tool
+fn scan_command_program(command: &str) -> String {
+    command
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Run a user-configured scan command, splitting the template on whitespace
+/// into the program and its arguments
+fn run_scan_command(command: &str) -> Result<Output, String> {
+    let mut parts = command.split_whitespace();
+    let program = parts
+        .next()
+        .ok_or_else(|| "Wi-Fi scan command is empty".to_string())?;
+
+    Command::new(program)
+        .args(parts)
+        .output()
+        .map_err(|e| format!("Failed to run Wi-Fi scan command '{}': {}", command, e))
+}
+
 #[cfg(target_os = "macos")]
-fn get_wifi_ssids_macos() -> Result<(Option<String>, Vec<String>), String> {
+fn get_wifi_ssids_macos(scan_command: Option<&str>) -> Result<WifiScan, String> {
     // Check if Wi-Fi is enabled
     let wifi_status = Command::new("networksetup")
         .args(["-getairportpower", "en0"])
@@ -338,11 +5717,14 @@ fn get_wifi_ssids_macos() -> Result<(Option<String>, Vec<String>), String> {
         sleep(Duration::from_secs(2));
     }
 
-    // Use system_profiler to get Wi-Fi information
-    let scan_output = Command::new("system_profiler")
-        .args(["SPAirPortDataType"])
-        .output()
-        .map_err(|e| format!("Failed to scan Wi-Fi networks: {}", e))?;
+    // Use system_profiler to get Wi-Fi information, unless a custom command is configured
+    let scan_output = match scan_command {
+        Some(command) => run_scan_command(command)?,
+        None => Command::new("system_profiler")
+            .args(["SPAirPortDataType"])
+            .output()
+            .map_err(|e| format!("Failed to scan Wi-Fi networks: {}", e))?,
+    };
 
     // Restore previous Wi-Fi state if it was disabled
     if wifi_was_disabled {
@@ -354,15 +5736,91 @@ fn get_wifi_ssids_macos() -> Result<(Option<String>, Vec<String>), String> {
 
     // Parse the output
     let output_str = String::from_utf8_lossy(&scan_output.stdout);
-    parse_wifi_output_macos(&output_str)
+    let (connected_ssid, ssids) = parse_wifi_output_macos(&output_str)?;
+
+    Ok(WifiScan {
+        connected_ssid,
+        ssids,
+        link_speed_mbps: parse_transmit_rate_macos(&output_str),
+        phy_mode: parse_phy_mode_macos(&output_str),
+        scan_tool: scan_command.map_or("system_profiler".to_string(), scan_command_program),
+        details: parse_network_details_macos(&output_str),
+    })
+}
+
+/// Parse the `Channel`, `Security` and `Signal / Noise` lines listed under each
+/// network in system_profiler output
+#[cfg(target_os = "macos")]
+fn parse_network_details_macos(output: &str) -> HashMap<String, NetworkDetails> {
+    let mut details: HashMap<String, NetworkDetails> = HashMap::new();
+    let mut current_ssid: Option<&str> = None;
+
+    for line in output.lines() {
+        let trimmed = line.trim();
+        if let Some(header) = trimmed.strip_suffix(':') {
+            current_ssid = Some(header.trim());
+            continue;
+        }
+        let Some(ssid) = current_ssid else {
+            continue;
+        };
+
+        if let Some(channel) = trimmed.strip_prefix("Channel:") {
+            details.entry(ssid.to_string()).or_default().channel = parse_leading_number(channel);
+        } else if let Some(security) = trimmed.strip_prefix("Security:") {
+            details.entry(ssid.to_string()).or_default().security =
+                Some(security.trim().to_string());
+        } else if let Some(signal) = trimmed.strip_prefix("Signal / Noise:") {
+            details.entry(ssid.to_string()).or_default().signal = parse_leading_number(signal);
+        }
+    }
+    details
+}
+
+/// Parse the `PHY Mode` of the connected network from system_profiler output
+#[cfg(target_os = "macos")]
+fn parse_phy_mode_macos(output: &str) -> Option<String> {
+    let mut in_current_network_section = false;
+
+    for line in output.lines() {
+        if line.contains("Current Network Information:") {
+            in_current_network_section = true;
+        } else if line.contains("Other Local Wi-Fi Networks:") {
+            return None;
+        } else if in_current_network_section
+            && let Some(phy_mode) = line.trim().strip_prefix("PHY Mode:")
+        {
+            return normalize_phy_mode(phy_mode);
+        }
+    }
+    None
+}
+
+/// Parse the `Transmit Rate` of the connected network from system_profiler output
+#[cfg(target_os = "macos")]
+fn parse_transmit_rate_macos(output: &str) -> Option<f64> {
+    let mut in_current_network_section = false;
+
+    for line in output.lines() {
+        if line.contains("Current Network Information:") {
+            in_current_network_section = true;
+        } else if line.contains("Other Local Wi-Fi Networks:") {
+            return None;
+        } else if in_current_network_section
+            && let Some(rate) = line.trim().strip_prefix("Transmit Rate:")
+        {
+            return parse_rate_mbps(rate);
+        }
+    }
+    None
 }
 
 #[cfg(target_os = "macos")]
 fn parse_wifi_output_macos(output: &str) -> Result<(Option<String>, Vec<String>), String> {
     // Use a regex to find SSIDs in the system_profiler output
     // This pattern looks for indented lines that end with a colon, following Network Information sections
-    let ssid_regex =
-        Regex::new(r"(?m)^\s+(.*?):\s*$").map_err(|e| format!("Invalid regex: {}", e))?;
+    static SSID_REGEX: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"(?m)^\s+(.*?):\s*$").expect("Invalid regex"));
 
     // Known section headers that aren't SSIDs
     let non_ssids = HashSet::from([
@@ -446,7 +5904,7 @@ fn parse_wifi_output_macos(output: &str) -> Result<(Option<String>, Vec<String>)
     }
 
     // Now collect all SSIDs from the entire output
-    for cap in ssid_regex.captures_iter(output) {
+    for cap in SSID_REGEX.captures_iter(output) {
         if let Some(m) = cap.get(1) {
             let potential_ssid = m.as_str().trim();
 
@@ -472,15 +5930,16 @@ fn parse_wifi_output_macos(output: &str) -> Result<(Option<String>, Vec<String>)
 }
 
 #[cfg(target_os = "linux")]
-fn get_wifi_ssids_linux() -> Result<(Option<String>, Vec<String>), String> {
-    // Check if Wi-Fi is enabled (using nmcli)
-    let wifi_status = Command::new("nmcli")
-        .args(&["radio", "wifi"])
-        .output()
-        .map_err(|e| format!("Failed to check Wi-Fi status: {}", e))?;
-
-    let status_str = String::from_utf8_lossy(&wifi_status.stdout);
-    let wifi_enabled = status_str.trim() == "enabled";
+fn get_wifi_ssids_linux(
+    scan_command: Option<&str>,
+    backends: &[String],
+    require_unprivileged: bool,
+) -> Result<WifiScan, String> {
+    // Check if Wi-Fi is enabled (using nmcli). Without nmcli, assume it is and leave it alone
+    let wifi_enabled = match Command::new("nmcli").args(&["radio", "wifi"]).output() {
+        Ok(wifi_status) => String::from_utf8_lossy(&wifi_status.stdout).trim() == "enabled",
+        Err(_) => true,
+    };
 
     let mut wifi_was_disabled = false;
 
@@ -497,39 +5956,23 @@ fn get_wifi_ssids_linux() -> Result<(Option<String>, Vec<String>), String> {
     }
 
     // Get currently connected network
-    let connected_network = if wifi_enabled || wifi_was_disabled {
-        let conn_output = Command::new("nmcli")
-            .args(&["-t", "connection", "show", "--active"])
-            .output()
-            .ok();
+    let mut connected_network = Command::new("nmcli")
+        .args(&["-t", "connection", "show", "--active"])
+        .output()
+        .ok()
+        .and_then(|output| {
+            parse_active_wifi_connection_linux(&String::from_utf8_lossy(&output.stdout))
+        });
 
-        if let Some(output) = conn_output {
-            let conn_str = String::from_utf8_lossy(&output.stdout);
-            for line in conn_str.lines() {
-                let parts: Vec<&str> = line.split(':').collect();
-                if parts.len() >= 3 && parts[2] == "wifi" {
-                    // Found connected Wi-Fi network
-                    return Ok((Some(parts[0].to_string()), vec![parts[0].to_string()]));
-                }
-            }
-        }
-        None
-    } else {
-        None
+    let scan_result = match scan_command {
+        // A custom command's output is parsed like nmcli/iwlist output
+        Some(command) => run_scan_command(command).and_then(|output| {
+            parse_wifi_output_linux(&String::from_utf8_lossy(&output.stdout))
+                .map(|(_, ssids)| (scan_command_program(command), ssids))
+        }),
+        None => scan_linux_backends(backends, require_unprivileged, &mut connected_network),
     };
 
-    // Try to scan with nmcli first (most common)
-    let scan_output = Command::new("nmcli")
-        .args(&["-t", "device", "wifi", "list"])
-        .output()
-        .or_else(|_| {
-            // Try with iwlist if nmcli fails
-            Command::new("iwlist")
-                .args(&["scanning"])
-                .output()
-                .map_err(|e| format!("Failed to scan Wi-Fi networks: {}", e))
-        })?;
-
     // Restore previous Wi-Fi state if it was disabled
     if wifi_was_disabled {
         Command::new("nmcli")
@@ -538,11 +5981,247 @@ fn get_wifi_ssids_linux() -> Result<(Option<String>, Vec<String>), String> {
             .ok(); // Ignore errors here
     }
 
-    // Parse the output
-    let output_str = String::from_utf8_lossy(&scan_output.stdout);
-    let (_, ssids) = parse_wifi_output_linux(&output_str)?;
+    let (scan_tool, mut ssids) = scan_result?;
+    add_connected_ssid(&mut ssids, connected_network.as_deref());
+
+    let link_speed_mbps = connected_network.as_ref().and_then(|_| link_speed_linux());
+    let phy_mode = connected_network.as_ref().and_then(|_| phy_mode_linux());
+
+    Ok(WifiScan {
+        connected_ssid: connected_network,
+        ssids,
+        link_speed_mbps,
+        phy_mode,
+        scan_tool,
+        details: HashMap::new(),
+    })
+}
+
+/// Wi-Fi standard of the connected network, going by the modulation `iw` reports
+/// in its tx bitrate, e.g. "866.7 MBit/s VHT-MCS 9 80MHz short GI VHT-NSS 2"
+#[cfg(target_os = "linux")]
+fn phy_mode_linux() -> Option<String> {
+    let interface = wireless_interface_linux()?;
+    let output = run_linux_backend("iw", &["dev", &interface, "link"]).ok()?;
+    let bitrate = output
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("tx bitrate:"))?;
+
+    let phy_mode = if bitrate.contains("EHT-") {
+        "802.11be"
+    } else if bitrate.contains("HE-") {
+        "802.11ax"
+    } else if bitrate.contains("VHT-") {
+        "802.11ac"
+    } else if bitrate.contains("MCS") {
+        "802.11n"
+    } else {
+        return None;
+    };
+    Some(phy_mode.to_string())
+}
+
+/// Scan with each backend in order until one works, returning the backend used
+/// and the SSIDs it found. When not running as root, backends that need root
+/// are tried last, or skipped if `require_unprivileged` is set. The iw backend
+/// also fills in the connected network when nmcli couldn't
+#[cfg(target_os = "linux")]
+fn scan_linux_backends(
+    backends: &[String],
+    require_unprivileged: bool,
+    connected_network: &mut Option<String>,
+) -> Result<(String, Vec<String>), String> {
+    let mut errors = Vec::new();
+
+    let unprivileged = !is_root_linux();
+    let needs_root = |backend: &String| {
+        unprivileged && LINUX_PRIVILEGED_WIFI_BACKENDS.contains(&backend.as_str())
+    };
+    let mut backends: Vec<&String> = backends.iter().collect();
+    backends.sort_by_key(|backend| needs_root(backend));
+
+    for backend in backends {
+        if needs_root(backend) && require_unprivileged {
+            errors.push(format!(
+                "{}: needs root and require_unprivileged_scan is set",
+                backend
+            ));
+            continue;
+        }
+
+        let result = match backend.as_str() {
+            "nmcli" => run_linux_backend(
+                "nmcli",
+                &["-t", "-f", "SSID,SIGNAL,CHAN,SECURITY", "device", "wifi", "list"],
+            )
+            .map(|output| parse_nmcli_ssids_linux(&output)),
+            "iwlist" => run_linux_backend("iwlist", &["scanning"])
+                .and_then(|output| parse_wifi_output_linux(&output).map(|(_, ssids)| ssids))
+                .and_then(|ssids| {
+                    // Without root, iwlist only reports cached results, which are often empty
+                    if ssids.is_empty() && unprivileged {
+                        eprintln!(
+                            "iwlist found no networks, probably because scanning needs root. nmcli can scan without it"
+                        );
+                        Err("no networks found without root".to_string())
+                    } else {
+                        Ok(ssids)
+                    }
+                }),
+            "iw" => wireless_interface_linux()
+                .ok_or_else(|| "no wireless interface found".to_string())
+                .and_then(|interface| {
+                    if connected_network.is_none() {
+                        *connected_network = run_linux_backend("iw", &["dev", &interface, "link"])
+                            .ok()
+                            .and_then(|output| parse_iw_ssids(&output).into_iter().next());
+                    }
+                    run_linux_backend("iw", &["dev", &interface, "scan"])
+                        .map(|output| parse_iw_ssids(&output))
+                }),
+            other => Err(format!("unknown backend '{}'", other)),
+        };
+
+        match result {
+            Ok(ssids) => return Ok((backend.clone(), ssids)),
+            Err(e) => errors.push(format!("{}: {}", backend, e)),
+        }
+    }
+
+    Err(format!(
+        "Failed to scan Wi-Fi networks ({})",
+        errors.join("; ")
+    ))
+}
+
+/// Whether the process runs as root, going by the effective UID in /proc
+#[cfg(target_os = "linux")]
+fn is_root_linux() -> bool {
+    std::fs::read_to_string("/proc/self/status").is_ok_and(|status| {
+        // Fields: real, effective, saved and filesystem UID
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix("Uid:"))
+            .and_then(|uids| uids.split_whitespace().nth(1))
+            == Some("0")
+    })
+}
+
+/// Run a scanning tool, failing if it can't be started or exits unsuccessfully
+#[cfg(target_os = "linux")]
+fn run_linux_backend(program: &str, args: &[&str]) -> Result<String, String> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| e.to_string())?;
+
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// First wireless interface, e.g. "wlan0"
+#[cfg(target_os = "linux")]
+fn wireless_interface_linux() -> Option<String> {
+    read_dir("/sys/class/net")
+        .ok()?
+        .flatten()
+        .find(|entry| entry.path().join("wireless").exists())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+}
+
+/// Extract the "SSID: ..." lines of `iw dev <iface> scan` or `iw dev <iface> link` output
+#[cfg(target_os = "linux")]
+fn parse_iw_ssids(output: &str) -> Vec<String> {
+    let ssids: HashSet<String> = output
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("SSID:"))
+        .map(|ssid| scanned_ssid(ssid.trim()))
+        .collect();
+
+    let mut ssids: Vec<String> = ssids.into_iter().collect();
+    ssids.sort();
+    ssids
+}
+
+/// Find the active Wi-Fi connection in `nmcli -t connection show --active` output
+/// (NAME:UUID:TYPE:DEVICE lines). The terse output names the type
+/// "802-11-wireless", older versions "wifi"
+#[cfg(target_os = "linux")]
+fn parse_active_wifi_connection_linux(output: &str) -> Option<String> {
+    output.lines().find_map(|line| {
+        let parts: Vec<&str> = line.split(':').collect();
+        (parts.len() >= 3 && matches!(parts[2], "802-11-wireless" | "wifi"))
+            .then(|| parts[0].to_string())
+    })
+}
 
-    Ok((connected_network, ssids))
+/// Sorted SSIDs of `nmcli -t -f SSID,SIGNAL,CHAN,SECURITY device wifi list` output
+#[cfg(target_os = "linux")]
+fn parse_nmcli_ssids_linux(output: &str) -> Vec<String> {
+    let mut ssids: Vec<String> = parse_network_details_linux(output).into_keys().collect();
+    ssids.sort();
+    ssids
+}
+
+/// Add the connected network to the sorted scan results, which can miss it
+#[cfg(target_os = "linux")]
+fn add_connected_ssid(ssids: &mut Vec<String>, connected_ssid: Option<&str>) {
+    if let Some(ssid) = connected_ssid
+        && !ssids.iter().any(|scanned| scanned == ssid)
+    {
+        ssids.push(ssid.to_string());
+        ssids.sort();
+    }
+}
+
+/// Get the rate of the in-use Wi-Fi network from nmcli
+#[cfg(target_os = "linux")]
+fn link_speed_linux() -> Option<f64> {
+    let output = Command::new("nmcli")
+        .args(["-t", "-f", "IN-USE,RATE", "device", "wifi", "list"])
+        .output()
+        .ok()?;
+
+    // The in-use network is marked with "*", e.g. "*:130 Mbit/s"
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| line.strip_prefix("*:"))
+        .and_then(parse_rate_mbps)
+}
+
+/// Parse `nmcli -t -f SSID,SIGNAL,CHAN,SECURITY device wifi list` output, where
+/// colons in the SSID are escaped as `\:`. Of networks seen by several access
+/// points, the strongest one is kept
+#[cfg(target_os = "linux")]
+fn parse_network_details_linux(output: &str) -> HashMap<String, NetworkDetails> {
+    let mut details: HashMap<String, NetworkDetails> = HashMap::new();
+
+    for line in output.lines() {
+        // Only the SSID can contain colons, so split from the end
+        let mut fields = line.rsplitn(4, ':');
+        let (Some(security), Some(channel), Some(signal), Some(ssid)) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+
+        let ssid = scanned_ssid(&ssid.replace("\\:", ":").replace("\\\\", "\\"));
+        let network = NetworkDetails {
+            signal: parse_leading_number(signal),
+            channel: parse_leading_number(channel),
+            security: Some(security.trim())
+                .filter(|security| !security.is_empty())
+                .map(str::to_string),
+        };
+
+        let known = details.get(&ssid).and_then(|known| known.signal);
+        if known.is_none_or(|known| network.signal.is_some_and(|signal| signal > known)) {
+            details.insert(ssid, network);
+        }
+    }
+    details
 }
 
 #[cfg(target_os = "linux")]
@@ -553,15 +6232,16 @@ fn parse_wifi_output_linux(output: &str) -> Result<(Option<String>, Vec<String>)
     // Check which tool's output we're dealing with
     if output.contains("SSID:") || output.contains(":SSID:") {
         // Parse nmcli output with regex
-        let nmcli_regex = Regex::new(r"(?m).*?:.*?:(.*?):")
-            .map_err(|e| format!("Invalid regex for nmcli: {}", e))?;
+        static NMCLI_REGEX: LazyLock<Regex> =
+            LazyLock::new(|| Regex::new(r"(?m).*?:.*?:(.*?):").expect("Invalid regex for nmcli"));
 
         // Look for the connected network (marked with *)
-        let connected_regex = Regex::new(r"(?m).*?\*:.*?:(.*?):")
-            .map_err(|e| format!("Invalid regex for nmcli connected: {}", e))?;
+        static CONNECTED_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+            Regex::new(r"(?m).*?\*:.*?:(.*?):").expect("Invalid regex for nmcli connected")
+        });
 
         // First try to find the connected network
-        for cap in connected_regex.captures_iter(output) {
+        for cap in CONNECTED_REGEX.captures_iter(output) {
             if let Some(m) = cap.get(1) {
                 let ssid = m.as_str().trim();
                 if !ssid.is_empty() {
@@ -572,29 +6252,23 @@ fn parse_wifi_output_linux(output: &str) -> Result<(Option<String>, Vec<String>)
         }
 
         // Then collect all networks
-        for cap in nmcli_regex.captures_iter(output) {
+        for cap in NMCLI_REGEX.captures_iter(output) {
             if let Some(m) = cap.get(1) {
-                let ssid = m.as_str().trim();
-                if !ssid.is_empty() {
-                    ssids.insert(ssid.to_string());
-                }
+                ssids.insert(scanned_ssid(m.as_str().trim()));
             }
         }
     } else if output.contains("ESSID:") {
         // Parse iwlist output with regex
-        let iwlist_regex = Regex::new(r#"ESSID:"([^"]*)"#)
-            .map_err(|e| format!("Invalid regex for iwlist: {}", e))?;
+        static IWLIST_REGEX: LazyLock<Regex> =
+            LazyLock::new(|| Regex::new(r#"ESSID:"([^"]*)"#).expect("Invalid regex for iwlist"));
 
         // Parse for currently connected network
         // Note: iwlist doesn't directly show connected state in scan results
         // This will be handled by the nmcli connection check earlier
 
-        for cap in iwlist_regex.captures_iter(output) {
+        for cap in IWLIST_REGEX.captures_iter(output) {
             if let Some(m) = cap.get(1) {
-                let ssid = m.as_str().trim();
-                if !ssid.is_empty() {
-                    ssids.insert(ssid.to_string());
-                }
+                ssids.insert(scanned_ssid(m.as_str().trim()));
             }
         }
     }
@@ -608,7 +6282,7 @@ fn parse_wifi_output_linux(output: &str) -> Result<(Option<String>, Vec<String>)
 
 // Windows
 #[cfg(target_os = "windows")]
-fn get_wifi_ssids_windows() -> Result<(Option<String>, Vec<String>), String> {
+fn get_wifi_ssids_windows(scan_command: Option<&str>) -> Result<WifiScan, String> {
     let connected_output = Command::new("powershell")
         .args(&["-Command", "netsh wlan show interfaces"])
         .output()
@@ -616,16 +6290,100 @@ fn get_wifi_ssids_windows() -> Result<(Option<String>, Vec<String>), String> {
 
     let connected_str = String::from_utf8_lossy(&connected_output.stdout);
     let connected_ssid = parse_connected_ssid_windows(&connected_str);
+    let link_speed_mbps = parse_transmit_rate_windows(&connected_str);
+    let phy_mode = parse_radio_type_windows(&connected_str);
 
-    let scan_output = Command::new("powershell")
-        .args(&["-Command", "netsh wlan show networks"])
-        .output()
-        .map_err(|e| format!("Failed to run netsh for networks: {}", e))?;
+    let scan_output = match scan_command {
+        Some(command) => run_scan_command(command)?,
+        None => Command::new("powershell")
+            .args(&["-Command", "netsh wlan show networks"])
+            .output()
+            .map_err(|e| format!("Failed to run netsh for networks: {}", e))?,
+    };
 
     let scan_str = String::from_utf8_lossy(&scan_output.stdout);
     let ssids = parse_wifi_output_windows(&scan_str)?;
 
-    Ok((connected_ssid, ssids))
+    Ok(WifiScan {
+        connected_ssid,
+        ssids,
+        link_speed_mbps,
+        phy_mode,
+        scan_tool: scan_command.map_or("netsh".to_string(), scan_command_program),
+        details: HashMap::new(),
+    })
+}
+
+/// Parse `netsh wlan show networks mode=bssid` output: the `Authentication` of
+/// each SSID, and the `Signal` and `Channel` of its strongest access point
+#[cfg(target_os = "windows")]
+fn parse_network_details_windows(output: &str) -> HashMap<String, NetworkDetails> {
+    static SSID_REGEX: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"^\s*SSID\s+\d+\s*:\s*(.*)$").expect("Regex error"));
+
+    let mut details: HashMap<String, NetworkDetails> = HashMap::new();
+    let mut current_ssid: Option<String> = None;
+    // Signal of the access point whose lines are being read
+    let mut bssid_signal: Option<i64> = None;
+
+    for line in output.lines() {
+        if let Some(caps) = SSID_REGEX.captures(line) {
+            current_ssid = Some(scanned_ssid(caps[1].trim()));
+            continue;
+        }
+        let Some(ssid) = &current_ssid else {
+            continue;
+        };
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let network = details.entry(ssid.clone()).or_default();
+
+        match key.trim() {
+            "Authentication" => network.security = Some(value.trim().to_string()),
+            "Signal" => bssid_signal = parse_leading_number(value),
+            // Listed after the Signal line of the same access point
+            "Channel"
+                if network
+                    .signal
+                    .is_none_or(|known| bssid_signal.is_some_and(|signal| signal > known)) =>
+            {
+                network.signal = bssid_signal;
+                network.channel = parse_leading_number(value);
+            }
+            _ => {}
+        }
+    }
+    details
+}
+
+/// Parse the `Radio type` line (e.g. "802.11ax") from `netsh wlan show interfaces` output
+#[cfg(target_os = "windows")]
+fn parse_radio_type_windows(output: &str) -> Option<String> {
+    output
+        .lines()
+        .map(str::trim)
+        .find(|line| line.starts_with("Radio type"))
+        .and_then(|line| line.split_once(':'))
+        .and_then(|(_, radio_type)| normalize_phy_mode(radio_type))
+}
+
+/// Lowercase a Wi-Fi standard such as "802.11AX", or `None` if it isn't an 802.11 standard
+#[cfg(any(target_os = "macos", target_os = "windows"))]
+fn normalize_phy_mode(phy_mode: &str) -> Option<String> {
+    let phy_mode = phy_mode.trim().to_lowercase();
+    phy_mode.starts_with("802.11").then_some(phy_mode)
+}
+
+/// Parse the `Transmit rate (Mbps)` line from `netsh wlan show interfaces` output
+#[cfg(target_os = "windows")]
+fn parse_transmit_rate_windows(output: &str) -> Option<f64> {
+    output
+        .lines()
+        .map(str::trim)
+        .find(|line| line.starts_with("Transmit rate"))
+        .and_then(|line| line.split_once(':'))
+        .and_then(|(_, rate)| parse_rate_mbps(rate))
 }
 
 #[cfg(target_os = "windows")]
@@ -647,15 +6405,12 @@ fn parse_connected_ssid_windows(output: &str) -> Option<String> {
 #[cfg(target_os = "windows")]
 fn parse_wifi_output_windows(output: &str) -> Result<Vec<String>, String> {
     let mut ssids = HashSet::new();
-    let ssid_regex =
-        Regex::new(r"^\s*SSID\s+\d+\s*:\s*(.+)$").map_err(|e| format!("Regex error: {}", e))?;
+    static SSID_REGEX: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"^\s*SSID\s+\d+\s*:\s*(.*)$").expect("Regex error"));
 
     for line in output.lines() {
-        if let Some(caps) = ssid_regex.captures(line) {
-            let ssid = caps[1].trim();
-            if !ssid.is_empty() {
-                ssids.insert(ssid.to_string());
-            }
+        if let Some(caps) = SSID_REGEX.captures(line) {
+            ssids.insert(scanned_ssid(caps[1].trim()));
         }
     }
 
@@ -663,3 +6418,513 @@ fn parse_wifi_output_windows(output: &str) -> Result<Vec<String>, String> {
     ssids_vec.sort();
     Ok(ssids_vec)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Empty directory under the system temp dir, unique to the test
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!(
+            "aw-watcher-network-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn later_drop_ins_take_precedence() {
+        let dir = temp_dir("drop-ins");
+        write(
+            dir.join("config.toml"),
+            "polling_interval = 10\nlatency_window = 1\nwifi_scan_interval = 100\n",
+        )
+        .unwrap();
+        create_dir_all(dir.join("config.d")).unwrap();
+        write(
+            dir.join("config.d").join("10-first.toml"),
+            "polling_interval = 20\nlatency_window = 2\n",
+        )
+        .unwrap();
+        write(
+            dir.join("config.d").join("20-second.toml"),
+            "polling_interval = 30\n",
+        )
+        .unwrap();
+
+        let config = AppConfig::load(Some(&dir), true).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // The later drop-in wins over the earlier one and config.toml
+        assert_eq!(config.polling_interval, 30);
+        // The earlier drop-in still wins over config.toml
+        assert_eq!(config.latency_window, 2);
+        // Settings no drop-in touches come from config.toml
+        assert_eq!(config.wifi_scan_interval, 100);
+    }
+
+    fn schedule(delays: &[u64], repeat: bool) -> RetrySchedule {
+        RetrySchedule {
+            delays: delays
+                .iter()
+                .map(|&millis| Duration::from_millis(millis))
+                .collect(),
+            repeat,
+        }
+    }
+
+    #[test]
+    fn retry_schedule_walks_the_list() {
+        let schedule = schedule(&[500, 1000, 2000], false);
+        assert_eq!(schedule.delay(0), Some(Duration::from_millis(500)));
+        assert_eq!(schedule.delay(1), Some(Duration::from_millis(1000)));
+        assert_eq!(schedule.delay(2), Some(Duration::from_millis(2000)));
+    }
+
+    #[test]
+    fn retry_schedule_repeats_the_last_delay() {
+        let schedule = schedule(&[500, 1000], true);
+        assert_eq!(schedule.delay(2), Some(Duration::from_millis(1000)));
+        assert_eq!(schedule.delay(100), Some(Duration::from_millis(1000)));
+        assert_eq!(schedule.bounded().delay(2), None);
+    }
+
+    #[test]
+    fn retry_schedule_gives_up_without_repeat() {
+        let schedule = schedule(&[0, 0], false);
+        assert_eq!(schedule.delay(2), None);
+
+        let mut attempts = 0;
+        let result: Result<(), String> = schedule.run("Test", || {
+            attempts += 1;
+            Err("down".to_string())
+        });
+        assert_eq!(result, Err("down".to_string()));
+        // The first attempt plus one retry per delay
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn retry_schedule_stops_on_success() {
+        let mut attempts = 0;
+        let result = schedule(&[0, 0, 0], true).run("Test", || {
+            attempts += 1;
+            if attempts == 2 {
+                Ok(attempts)
+            } else {
+                Err("down".to_string())
+            }
+        });
+        assert_eq!(result, Ok(2));
+    }
+
+    #[test]
+    fn empty_retry_schedule_tries_once() {
+        let mut attempts = 0;
+        let result: Result<(), String> = schedule(&[], true).run("Test", || {
+            attempts += 1;
+            Err("down".to_string())
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    const PLACEHOLDERS: [(&str, &str); 4] = [
+        ("hostname", "laptop"),
+        ("user", "alice"),
+        ("os", "linux"),
+        ("iface", "wlan0"),
+    ];
+
+    #[test]
+    fn bucket_template_expands_each_placeholder() {
+        let expand = |template| expand_bucket_template(template, &PLACEHOLDERS);
+        assert_eq!(expand("net_{hostname}"), Ok("net_laptop".to_string()));
+        assert_eq!(expand("net_{user}"), Ok("net_alice".to_string()));
+        assert_eq!(expand("net_{os}"), Ok("net_linux".to_string()));
+        assert_eq!(expand("net_{iface}"), Ok("net_wlan0".to_string()));
+        assert_eq!(
+            expand("{user}@{hostname}_{os}_{iface}"),
+            Ok("alice@laptop_linux_wlan0".to_string())
+        );
+        assert_eq!(expand("plain-bucket"), Ok("plain-bucket".to_string()));
+    }
+
+    #[test]
+    fn bucket_template_rejects_unknown_placeholders() {
+        let expand = |template| expand_bucket_template(template, &PLACEHOLDERS);
+        assert_eq!(
+            expand("net_{host}"),
+            Err("Unknown placeholder {host} in bucket ID 'net_{host}'".to_string())
+        );
+        assert_eq!(
+            expand("net_{}"),
+            Err("Unknown placeholder {} in bucket ID 'net_{}'".to_string())
+        );
+    }
+
+    #[test]
+    fn bucket_template_rejects_unmatched_braces() {
+        let expand = |template| expand_bucket_template(template, &PLACEHOLDERS);
+        assert_eq!(
+            expand("net_{hostname"),
+            Err("Unclosed placeholder in bucket ID 'net_{hostname'".to_string())
+        );
+        assert_eq!(
+            expand("net_hostname}"),
+            Err("Unmatched '}' in bucket ID 'net_hostname}'".to_string())
+        );
+        assert_eq!(
+            expand("net}_{hostname}"),
+            Err("Unmatched '}' in bucket ID 'net}_{hostname}'".to_string())
+        );
+        assert_eq!(
+            expand("{hostname}}"),
+            Err("Unmatched '}' in bucket ID '{hostname}}'".to_string())
+        );
+    }
+
+    fn config_with_intervals(polling_interval: u64, wifi_scan_interval: u64) -> AppConfig {
+        AppConfig {
+            polling_interval,
+            wifi_scan_interval,
+            ..AppConfig::default()
+        }
+    }
+
+    #[test]
+    fn zero_intervals_are_reset() {
+        let mut config = config_with_intervals(0, 0);
+        assert_eq!(
+            config.reset_invalid_intervals(),
+            vec![
+                ("polling_interval", 0, default_polling_interval()),
+                ("wifi_scan_interval", 0, default_wifi_scan_interval()),
+            ]
+        );
+        assert_eq!(config.polling_interval, default_polling_interval());
+        assert_eq!(config.wifi_scan_interval, default_wifi_scan_interval());
+    }
+
+    #[test]
+    fn normal_intervals_are_kept() {
+        let mut config = config_with_intervals(30, MAX_INTERVAL_SECONDS);
+        assert_eq!(config.reset_invalid_intervals(), vec![]);
+        assert_eq!(config.polling_interval, 30);
+        assert_eq!(config.wifi_scan_interval, MAX_INTERVAL_SECONDS);
+    }
+
+    #[test]
+    fn extreme_intervals_are_reset() {
+        let mut config = config_with_intervals(MAX_INTERVAL_SECONDS + 1, u64::MAX);
+        assert_eq!(
+            config.reset_invalid_intervals(),
+            vec![
+                (
+                    "polling_interval",
+                    MAX_INTERVAL_SECONDS + 1,
+                    default_polling_interval()
+                ),
+                ("wifi_scan_interval", u64::MAX, default_wifi_scan_interval()),
+            ]
+        );
+        assert_eq!(config.polling_interval, default_polling_interval());
+        assert_eq!(config.wifi_scan_interval, default_wifi_scan_interval());
+    }
+
+    #[test]
+    fn only_invalid_intervals_are_reported() {
+        let mut config = config_with_intervals(10, 0);
+        assert_eq!(
+            config.reset_invalid_intervals(),
+            vec![("wifi_scan_interval", 0, default_wifi_scan_interval())]
+        );
+        assert_eq!(config.polling_interval, 10);
+        assert_eq!(config.wifi_scan_interval, default_wifi_scan_interval());
+    }
+
+    #[test]
+    fn unbracketed_ipv6_targets_are_bracketed() {
+        assert_eq!(
+            bracketed_ipv6_target("2606:4700:4700::1111:53"),
+            Some("[2606:4700:4700::1111]:53".to_string())
+        );
+        assert_eq!(
+            bracketed_ipv6_target("::1:443"),
+            Some("[::1]:443".to_string())
+        );
+    }
+
+    #[test]
+    fn timeout_suffix_is_kept_when_bracketing() {
+        assert_eq!(
+            bracketed_ipv6_target("2606:4700::1111:53@500ms"),
+            Some("[2606:4700::1111]:53@500ms".to_string())
+        );
+        assert_eq!(
+            bracketed_ipv6_target("2606:4700::1111:53@2s"),
+            Some("[2606:4700::1111]:53@2s".to_string())
+        );
+    }
+
+    #[test]
+    fn valid_targets_are_left_alone() {
+        for target in [
+            "[2606:4700:4700::1111]:53",
+            "[2606:4700::1111]:53@500ms",
+            "1.1.1.1:53",
+            "1.1.1.1:53@500ms",
+            "example.com:443",
+        ] {
+            assert_eq!(bracketed_ipv6_target(target), None, "{}", target);
+        }
+    }
+
+    #[test]
+    fn malformed_targets_are_left_alone() {
+        for target in [
+            // Not an IPv6 address before the last colon
+            "example:com:443",
+            "2606:4700::zzzz:53",
+            // Port out of range or not a number
+            "2606:4700::1111:70000",
+            "2606:4700::1111:dns",
+            // Invalid timeout suffix, reported by validate_target_timeouts
+            "2606:4700::1111:53@fast",
+            "2606:4700::1111:53@0ms",
+        ] {
+            assert_eq!(bracketed_ipv6_target(target), None, "{}", target);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    const NMCLI_ACTIVE_CONNECTIONS: &str = "\
+Wired connection 1:3c1a9b2e-5d4f-4f6e-9a3b-2f1d0c8e7a61:802-3-ethernet:enp0s31f6
+HomeNet:8f2d6c4a-1b3e-4c5d-9e7f-0a1b2c3d4e5f:802-11-wireless:wlp2s0
+lo:0d9c8b7a-6f5e-4d3c-2b1a-098765432100:loopback:lo
+";
+
+    #[cfg(target_os = "linux")]
+    const NMCLI_WIFI_LIST: &str = "\
+HomeNet:70:6:WPA2
+Cafe\\:Guest:42:11:
+Neighbour 5G:31:36:WPA2 WPA3
+HomeNet:55:44:WPA2
+";
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn connected_ssid_is_found_among_active_connections() {
+        assert_eq!(
+            parse_active_wifi_connection_linux(NMCLI_ACTIVE_CONNECTIONS),
+            Some("HomeNet".to_string())
+        );
+        assert_eq!(
+            parse_active_wifi_connection_linux("HomeNet:8f2d6c4a:wifi:wlp2s0\n"),
+            Some("HomeNet".to_string())
+        );
+        assert_eq!(
+            parse_active_wifi_connection_linux(
+                "Wired connection 1:3c1a9b2e:802-3-ethernet:enp0s31f6\n"
+            ),
+            None
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn connected_scan_lists_every_visible_network() {
+        let connected = parse_active_wifi_connection_linux(NMCLI_ACTIVE_CONNECTIONS);
+        let mut ssids = parse_nmcli_ssids_linux(NMCLI_WIFI_LIST);
+        add_connected_ssid(&mut ssids, connected.as_deref());
+
+        assert_eq!(connected.as_deref(), Some("HomeNet"));
+        assert_eq!(ssids, vec!["Cafe:Guest", "HomeNet", "Neighbour 5G"]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn connected_ssid_missed_by_the_scan_is_listed() {
+        let mut ssids = parse_nmcli_ssids_linux("Neighbour 5G:31:36:WPA2\n");
+        add_connected_ssid(&mut ssids, Some("HomeNet"));
+        assert_eq!(ssids, vec!["HomeNet", "Neighbour 5G"]);
+    }
+
+    fn target_list(targets: &[&str]) -> Vec<String> {
+        targets.iter().map(|target| target.to_string()).collect()
+    }
+
+    #[test]
+    fn degraded_probes_use_targets_on_their_port() {
+        let targets = target_list(&["1.1.1.1:443", "8.8.8.8:53@500ms", "9.9.9.9:53"]);
+        assert_eq!(
+            target_on_port(&targets, 53),
+            Some("8.8.8.8:53@500ms".to_string())
+        );
+        assert_eq!(
+            target_on_port(&targets, 443),
+            Some("1.1.1.1:443".to_string())
+        );
+    }
+
+    #[test]
+    fn degraded_probes_fall_back_to_the_first_host() {
+        let targets = target_list(&["example.com:80@2s", "1.1.1.1:443"]);
+        assert_eq!(
+            target_on_port(&targets, 53),
+            Some("example.com:53@2s".to_string())
+        );
+
+        let targets = target_list(&["[2606:4700:4700::1111]:80"]);
+        assert_eq!(
+            target_on_port(&targets, 443),
+            Some("[2606:4700:4700::1111]:443".to_string())
+        );
+    }
+
+    #[test]
+    fn degraded_probes_skip_unusable_targets() {
+        assert_eq!(target_on_port(&[], 53), None);
+        let targets = target_list(&["no-port", "1.1.1.1:53@fast", "1.1.1.1:80"]);
+        assert_eq!(target_on_port(&targets, 53), Some("1.1.1.1:53".to_string()));
+    }
+
+    #[cfg(target_os = "linux")]
+    const PROC_NET_ROUTE: &str = "\
+Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+wlp2s0\t00000000\t0101A8C0\t0003\t0\t0\t600\t00000000\t0\t0\t0
+enp0s31f6\t00000000\tFE01A8C0\t0003\t0\t0\t100\t00000000\t0\t0\t0
+enp0s31f6\t0001A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0
+";
+
+    #[cfg(all(target_os = "linux", target_endian = "little"))]
+    #[test]
+    fn default_route_has_the_lowest_metric() {
+        assert_eq!(
+            parse_default_route_linux(PROC_NET_ROUTE),
+            Some((
+                "enp0s31f6".to_string(),
+                Some(Ipv4Addr::new(192, 168, 1, 254))
+            ))
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn on_link_default_route_has_no_gateway() {
+        let routes = "Iface\tDestination\tGateway\tFlags\tRefCnt\tUse\tMetric\tMask\n\
+                      tun0\t00000000\t00000000\t0001\t0\t0\t0\t00000000\n";
+        assert_eq!(
+            parse_default_route_linux(routes),
+            Some(("tun0".to_string(), None))
+        );
+        // Only a route to the local subnet
+        let routes = "Iface\tDestination\tGateway\tFlags\tRefCnt\tUse\tMetric\tMask\n\
+                      eth0\t0001A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\n";
+        assert_eq!(parse_default_route_linux(routes), None);
+    }
+
+    #[test]
+    fn retry_schedule_stops_when_retries_are_no_longer_due() {
+        let mut attempts = 0;
+        let result: Result<(), String> = schedule(&[0, 0, 0], true).run_while(
+            "Test",
+            || false,
+            || {
+                attempts += 1;
+                Err("down".to_string())
+            },
+        );
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn base_path_slashes_are_normalized() {
+        for path in [
+            "activitywatch",
+            "/activitywatch",
+            "activitywatch/",
+            "//activitywatch//",
+        ] {
+            assert_eq!(
+                normalize_base_path(path),
+                Ok(Some("/activitywatch".to_string())),
+                "{}",
+                path
+            );
+        }
+        assert_eq!(
+            normalize_base_path("/tools/aw-server_1.0/"),
+            Ok(Some("/tools/aw-server_1.0".to_string()))
+        );
+        assert_eq!(normalize_base_path(""), Ok(None));
+        assert_eq!(normalize_base_path("/"), Ok(None));
+    }
+
+    #[test]
+    fn invalid_base_paths_are_rejected() {
+        for path in [
+            "/activity watch",
+            "/aw?x=1",
+            "/aw#top",
+            "/../aw",
+            "/aw/.",
+            "http://example.com/aw",
+        ] {
+            assert!(normalize_base_path(path).is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn server_urls_include_the_base_path() {
+        let base_path = normalize_base_path("activitywatch/").unwrap().unwrap();
+        assert_eq!(
+            server_url(&base_path, "info"),
+            "http://localhost:5600/activitywatch/api/0/info"
+        );
+        assert_eq!(
+            server_url(&base_path, "buckets/aw-watcher-network_laptop"),
+            "http://localhost:5600/activitywatch/api/0/buckets/aw-watcher-network_laptop"
+        );
+        assert_eq!(
+            server_url(
+                &base_path,
+                "buckets/aw-watcher-network_laptop/heartbeat?pulsetime=5"
+            ),
+            "http://localhost:5600/activitywatch/api/0/buckets/aw-watcher-network_laptop/heartbeat?pulsetime=5"
+        );
+        assert_eq!(
+            server_url(&base_path, "buckets/aw-watcher-network_laptop/events"),
+            "http://localhost:5600/activitywatch/api/0/buckets/aw-watcher-network_laptop/events"
+        );
+        assert_eq!(server_url("", "info"), "http://localhost:5600/api/0/info");
+    }
+
+    #[test]
+    fn ssid_polling_intervals_are_range_checked() {
+        for interval in [0, MAX_INTERVAL_SECONDS + 1] {
+            let mut config = AppConfig::default();
+            config
+                .ssid_polling_intervals
+                .insert("HomeNet".to_string(), interval);
+            assert_eq!(
+                config.apply_networks(),
+                Err(format!(
+                    "Invalid polling_interval of {}s for SSID 'HomeNet' in [ssid_polling_intervals] (must be between 1 and {})",
+                    interval, MAX_INTERVAL_SECONDS
+                ))
+            );
+        }
+
+        let mut config = AppConfig::default();
+        config
+            .ssid_polling_intervals
+            .insert("HomeNet".to_string(), 60);
+        assert_eq!(config.apply_networks(), Ok(()));
+        assert_eq!(config.polling_interval_for(Some("homenet")), 60);
+    }
+}